@@ -1,306 +1,1751 @@
 use serde::Deserialize;
 use sqlparser::ast::{
-    Expr, FunctionArg, FunctionArgExpr, SelectItem, SetExpr, Statement, TableFactor, TableWithJoins,
+    Assignment, Cte, ExcludeSelectItem, Expr, Function, GroupByExpr, Ident, Join, JoinConstraint,
+    JoinOperator, MergeClause, NamedWindowDefinition, ObjectName, Query, RenameSelectItem, Select,
+    SelectItem, SetExpr, Statement, TableFactor, TableWithJoins, Value, WildcardAdditionalOptions,
 };
+use sqlparser::parser::Parser;
+use sqlparser::tokenizer::{Location, Token, Tokenizer};
 use std::collections::HashMap;
-#[derive(Debug, Deserialize, PartialEq)]
+use std::{iter, slice};
+
+use crate::dialect::Dialect;
+use crate::error::LineageError;
+use crate::expr::{
+    column_references, filtering_subqueries, influencing_exprs, subqueries, transformed_references,
+    Reference,
+};
+use crate::functions::{default_functions, FunctionDefinition};
+use crate::lineage::{
+    ClauseKind, ClauseLineage, ColumnLineage, SourceSpan, SourceStatement, StatementLineage,
+    Transformation,
+};
+#[derive(Debug, Clone, Deserialize, PartialEq)]
 pub struct FullColumn {
     pub database_name: String,
     pub schema_name: String,
     pub table_name: String,
     pub column_name: String,
 }
+impl FullColumn {
+    /// The `database.schema.table.column` identifier of this column.
+    pub fn full_name(&self) -> String {
+        format!("{}.{}", self.table_full_name(), self.column_name)
+    }
+    /// The `database.schema.table` identifier of the table of this column,
+    /// leaving out a database or schema a table created by a statement
+    /// doesn't have.
+    pub fn table_full_name(&self) -> String {
+        [&self.database_name, &self.schema_name, &self.table_name]
+            .into_iter()
+            .filter(|part| !part.is_empty())
+            .map(String::as_str)
+            .collect::<Vec<&str>>()
+            .join(".")
+    }
+}
+impl From<&FullColumn> for ColumnLineage {
+    fn from(meta: &FullColumn) -> Self {
+        Self {
+            name: meta.column_name.clone(),
+            upstream: vec![(meta.full_name(), Transformation::Identity)],
+            ambiguous: false,
+            indirect: Vec::new(),
+        }
+    }
+}
+/// The current database and schema search path, which table names
+/// without them are looked up in. A run of statements starts with the
+/// configured ones, and `USE` and `SET search_path` change them for the
+/// statements after them.
+#[derive(Debug, Clone, Default)]
+struct Session {
+    database: Option<String>,
+    search_path: Vec<String>,
+}
+/// The names visible while resolving a SELECT.
+#[derive(Debug, Clone)]
+struct Scope {
+    /// The index of the statement being resolved.
+    statement: usize,
+    /// Where the statement being resolved starts.
+    location: Location,
+    /// Common table expressions by name, with their output columns.
+    ctes: HashMap<String, Vec<ColumnLineage>>,
+    /// The FROM clause of the SELECT.
+    from: Vec<TableWithJoins>,
+    /// The windows the SELECT defines in its WINDOW clause.
+    windows: Vec<NamedWindowDefinition>,
+    /// The columns of each relation of the FROM clause, resolved once for
+    /// the whole SELECT.
+    relations: Vec<(TableFactor, Vec<ColumnLineage>)>,
+    /// The current database and search path of the statement.
+    session: Session,
+    /// The scope of the query this is a subquery of, or of the relations
+    /// before a `LATERAL` derived table, whose columns a correlated
+    /// subquery can reference when its own relations don't have them.
+    outer: Option<Box<Scope>>,
+}
+impl Scope {
+    fn new(statement: usize, location: Location) -> Self {
+        Self {
+            statement,
+            location,
+            ctes: HashMap::new(),
+            from: Vec::new(),
+            windows: Vec::new(),
+            relations: Vec::new(),
+            session: Session::default(),
+            outer: None,
+        }
+    }
+    /// The scope of a subquery of an expression of this scope.
+    fn subquery(&self) -> Self {
+        Self {
+            from: Vec::new(),
+            windows: Vec::new(),
+            relations: Vec::new(),
+            outer: Some(Box::new(self.clone())),
+            ..self.clone()
+        }
+    }
+    fn with_from(&self, from: Vec<TableWithJoins>) -> Self {
+        Self {
+            from,
+            relations: Vec::new(),
+            ..self.clone()
+        }
+    }
+    fn unsupported(&self, construct: impl ToString) -> LineageError {
+        LineageError::Unsupported {
+            statement: self.statement,
+            location: self.location,
+            construct: construct.to_string(),
+        }
+    }
+    fn ambiguous_column(&self, column: String, candidates: Vec<String>) -> LineageError {
+        LineageError::AmbiguousColumn {
+            statement: self.statement,
+            location: self.location,
+            column,
+            candidates,
+        }
+    }
+    fn unknown_table(&self, table: impl ToString) -> LineageError {
+        LineageError::UnknownTable {
+            statement: self.statement,
+            location: self.location,
+            table: table.to_string(),
+        }
+    }
+    fn column_count_mismatch(&self, target: String, expected: usize, found: usize) -> LineageError {
+        LineageError::ColumnCountMismatch {
+            statement: self.statement,
+            location: self.location,
+            target,
+            expected,
+            found,
+        }
+    }
+}
 #[derive(Debug, Deserialize, PartialEq)]
 pub struct QueryAnalytics {
     metadata: Vec<FullColumn>,
-    pub dependency_map: HashMap<String, (Vec<String>, bool)>,
+    pub dependency_map: HashMap<String, Vec<(String, Transformation)>>,
+    #[serde(default)]
+    dialect: Dialect,
+    /// Whether an ambiguous column reference keeps every candidate instead
+    /// of failing the statement.
+    #[serde(default)]
+    permissive: bool,
+    /// How lineage flows through calls to functions, by lowercase function
+    /// name.
+    #[serde(default = "default_functions")]
+    functions: HashMap<String, FunctionDefinition>,
+    /// The database table names without one are looked up in.
+    #[serde(default)]
+    database: Option<String>,
+    /// The schemas table names without one are looked up in, in order.
+    #[serde(default)]
+    search_path: Vec<String>,
 }
 
 impl QueryAnalytics {
+    pub fn with_dialect(mut self, dialect: Dialect) -> Self {
+        self.dialect = dialect;
+        self
+    }
+
+    pub fn with_permissive(mut self, permissive: bool) -> Self {
+        self.permissive = permissive;
+        self
+    }
+
+    pub fn with_database(mut self, database: Option<String>) -> Self {
+        self.database = database;
+        self
+    }
+
+    pub fn with_search_path(mut self, search_path: Vec<String>) -> Self {
+        self.search_path = search_path;
+        self
+    }
+
+    /// The name `ident` refers to in the dialect of the queries.
+    fn ident(&self, ident: &Ident) -> String {
+        self.dialect.fold_identifier(ident)
+    }
+
+    /// Adds `functions` to the function catalog, replacing the definitions
+    /// of functions of the same name.
+    pub fn with_functions(mut self, functions: Vec<FunctionDefinition>) -> Self {
+        for definition in functions {
+            self.functions
+                .insert(definition.function_name.to_lowercase(), definition);
+        }
+        self
+    }
+
     fn add_dependency(
         &mut self,
         dependent_column: String,
         upstream_identifier: String,
-        new_opacity: bool,
+        transformation: Transformation,
     ) {
-        if let Some((dependent_column, opacity)) = self.dependency_map.get_mut(&dependent_column) {
-            // TODO: make this a hashset.
-            if !dependent_column.contains(&upstream_identifier) {
-                dependent_column.push(upstream_identifier);
-                *opacity = new_opacity;
-            }
+        let upstream = self.dependency_map.entry(dependent_column).or_default();
+        // TODO: make this a hashmap.
+        if let Some((_, known)) = upstream
+            .iter_mut()
+            .find(|(identifier, _)| *identifier == upstream_identifier)
+        {
+            *known = transformation.max(*known);
         } else {
-            self.dependency_map.insert(
-                dependent_column.clone(),
-                (vec![upstream_identifier], new_opacity),
-            );
+            upstream.push((upstream_identifier, transformation));
         }
     }
 
+    /// Adds `column` to `columns`, merging its lineage into the output
+    /// column of the same name if there already is one.
+    fn add_column(columns: &mut Vec<ColumnLineage>, column: ColumnLineage) {
+        if let Some(known) = columns.iter_mut().find(|v| v.name == column.name) {
+            Self::merge_column(known, column);
+        } else {
+            columns.push(column);
+        }
+    }
+
+    /// Looks up `column_name` among the relations of the FROM clause in
+    /// `scope`, or only among those `qualifier` refers to if it is
+    /// qualified.
     fn search_for_columns_in_tables(
         &self,
-        from: Vec<TableWithJoins>,
-        column_name: String,
-        table_name: Option<String>,
-    ) -> Option<String> {
-        let mut needle: Option<String> = None;
-        if let Some(table_name) = table_name {
-            for meta in &self.metadata {
-                if column_name == meta.column_name && table_name == meta.table_name {
-                    needle = Some(format!(
-                        "{}.{}.{}.{}",
-                        meta.database_name, meta.schema_name, meta.table_name, meta.column_name
-                    ));
-                    return needle;
+        scope: &Scope,
+        column_name: Ident,
+        qualifier: Option<ObjectName>,
+    ) -> Result<Option<ColumnLineage>, LineageError> {
+        if let Some(qualifier) = &qualifier {
+            if !Self::from_relations(&scope.from)
+                .into_iter()
+                .any(|relation| self.relation_matches(relation, qualifier))
+            {
+                // A correlated subquery can reference the tables of the
+                // queries around it.
+                return match &scope.outer {
+                    Some(outer) => self.search_for_columns_in_tables(
+                        outer,
+                        column_name,
+                        Some(qualifier.clone()),
+                    ),
+                    None => Err(scope.unknown_table(qualifier)),
+                };
+            }
+        }
+        let mut candidates = Vec::new();
+        self.search_for_column_in_joins(
+            scope,
+            &scope.from,
+            &column_name,
+            qualifier.as_ref(),
+            &mut candidates,
+        )?;
+        // A qualifier that refers to one of the relations here hides the
+        // relations of the same name around it, whether or not it has the
+        // column.
+        if candidates.is_empty() && qualifier.is_none() {
+            if let Some(outer) = &scope.outer {
+                return self.search_for_columns_in_tables(outer, column_name, qualifier);
+            }
+        }
+        if candidates.len() > 1 {
+            if !self.permissive {
+                return Err(scope.ambiguous_column(
+                    self.ident(&column_name),
+                    candidates
+                        .into_iter()
+                        .flat_map(|v| v.upstream.into_iter().map(|(upstream, _)| upstream))
+                        .collect(),
+                ));
+            }
+            // Keep every candidate, flagged as ambiguous.
+            for candidate in &mut candidates {
+                candidate.ambiguous = true;
+            }
+        }
+        let mut candidates = candidates.into_iter();
+        let mut needle = candidates.next();
+        if let Some(needle) = &mut needle {
+            for candidate in candidates {
+                Self::merge_column(needle, candidate);
+            }
+        }
+        Ok(needle)
+    }
+    /// Adds the columns named `column_name` of the relations in `from` to
+    /// `candidates`. A column a join is `USING`, or that both sides of a
+    /// `NATURAL` join have, is a single column coalesced from both sides.
+    fn search_for_column_in_joins(
+        &self,
+        scope: &Scope,
+        from: &[TableWithJoins],
+        column_name: &Ident,
+        qualifier: Option<&ObjectName>,
+        candidates: &mut Vec<ColumnLineage>,
+    ) -> Result<(), LineageError> {
+        for TableWithJoins { relation, joins } in from {
+            let start = candidates.len();
+            self.search_for_column_in_factor(scope, relation, column_name, qualifier, candidates)?;
+            for join in joins {
+                let mut found = Vec::new();
+                self.search_for_column_in_factor(
+                    scope,
+                    &join.relation,
+                    column_name,
+                    qualifier,
+                    &mut found,
+                )?;
+                let coalesced = qualifier.is_none()
+                    && match Self::join_constraint(&join.join_operator) {
+                        Some(JoinConstraint::Using(ids)) => ids
+                            .iter()
+                            .any(|id| self.dialect.identifiers_match(id, column_name)),
+                        Some(JoinConstraint::Natural) => true,
+                        _ => false,
+                    };
+                match candidates.get_mut(start) {
+                    Some(left) if coalesced => {
+                        for column in found {
+                            Self::merge_column(left, column);
+                        }
+                    }
+                    _ => candidates.extend(found),
                 }
             }
-        } else {
-            for TableWithJoins { relation, .. } in &from {
+        }
+        Ok(())
+    }
+    /// Adds the column named `column_name` of `relation`, or of the
+    /// relations it joins, to `candidates` if `qualifier` refers to it.
+    fn search_for_column_in_factor(
+        &self,
+        scope: &Scope,
+        relation: &TableFactor,
+        column_name: &Ident,
+        qualifier: Option<&ObjectName>,
+        candidates: &mut Vec<ColumnLineage>,
+    ) -> Result<(), LineageError> {
+        match relation {
+            TableFactor::NestedJoin {
+                table_with_joins, ..
+            } => self.search_for_column_in_joins(
+                scope,
+                slice::from_ref(table_with_joins),
+                column_name,
+                qualifier,
+                candidates,
+            ),
+            relation
+                if qualifier.is_none_or(|qualifier| self.relation_matches(relation, qualifier)) =>
+            {
+                candidates.extend(self.search_for_column_in_relation(
+                    scope,
+                    relation,
+                    column_name,
+                )?);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+    /// The constraint of a join, if it has one.
+    fn join_constraint(operator: &JoinOperator) -> Option<&JoinConstraint> {
+        match operator {
+            JoinOperator::Inner(constraint)
+            | JoinOperator::LeftOuter(constraint)
+            | JoinOperator::RightOuter(constraint)
+            | JoinOperator::FullOuter(constraint)
+            | JoinOperator::LeftSemi(constraint)
+            | JoinOperator::RightSemi(constraint)
+            | JoinOperator::LeftAnti(constraint)
+            | JoinOperator::RightAnti(constraint) => Some(constraint),
+            JoinOperator::CrossJoin | JoinOperator::CrossApply | JoinOperator::OuterApply => None,
+        }
+    }
+    /// Looks up `column_name` among the columns `relation` exposes.
+    fn search_for_column_in_relation(
+        &self,
+        scope: &Scope,
+        relation: &TableFactor,
+        column_name: &Ident,
+    ) -> Result<Option<ColumnLineage>, LineageError> {
+        Ok(self
+            .relation_columns(scope, relation)?
+            .into_iter()
+            .find(|v| self.dialect.identifier_matches(column_name, &v.name)))
+    }
+    /// Every relation of a FROM clause, including the ones it joins.
+    fn from_relations(from: &[TableWithJoins]) -> Vec<&TableFactor> {
+        let mut relations = Vec::new();
+        for TableWithJoins { relation, joins } in from {
+            for relation in iter::once(relation).chain(joins.iter().map(|join| &join.relation)) {
                 match relation {
-                    TableFactor::Table { name, .. } => {
-                        let full_name = name.0.clone();
-                        let potential_table_name: Option<String> =
-                            full_name.last().map(|v| v.value.clone());
-                        let schema_name: Option<String> = if full_name.len() > 1 {
-                            full_name.first().map(|v| v.value.clone())
-                        } else {
-                            None
-                        };
-                        let potential_table_name = potential_table_name
-                            .expect("Could not retrieve table name out of struct");
-                        for meta in &self.metadata {
-                            if column_name == meta.column_name
-                                && ((table_name.is_none()
-                                    && meta.table_name == potential_table_name)
-                                    || (table_name.is_some()
-                                        && Some(meta.table_name.clone()) == table_name))
-                                && ((schema_name.is_some()
-                                    && Some(meta.schema_name.clone()) == schema_name)
-                                    || schema_name.is_none())
-                            {
-                                needle = Some(format!(
-                                    "{}.{}.{}.{}",
-                                    meta.database_name,
-                                    meta.schema_name,
-                                    meta.table_name,
-                                    meta.column_name
-                                ));
-                                return needle;
-                            }
+                    TableFactor::NestedJoin {
+                        table_with_joins, ..
+                    } => relations.extend(Self::from_relations(slice::from_ref(table_with_joins))),
+                    relation => relations.push(relation),
+                }
+            }
+        }
+        relations
+    }
+    /// The catalog columns of the table `name` refers to, written as
+    /// `table`, `schema.table` or `database.schema.table`. A name without
+    /// a database is looked up in the current database, and one without a
+    /// schema in the first schema of the search path that has the table.
+    /// Without those, the first table of that name in the catalog wins.
+    fn catalog_columns(&self, scope: &Scope, name: &ObjectName) -> Vec<&FullColumn> {
+        let parts = self.dialect.object_name_parts(name);
+        let (database, schema, table) = match parts.as_slice() {
+            [table] => (None, None, table),
+            [schema, table] => (None, Some(schema), table),
+            [database, schema, table] => (Some(database), Some(schema), table),
+            _ => return Vec::new(),
+        };
+        let matches = |ident: &Ident, name: &str| self.dialect.identifier_matches(ident, name);
+        let matches_setting =
+            |setting: &str, name: &str| self.dialect.identifier_matches(&Ident::new(setting), name);
+        let tables =
+            self.metadata
+                .iter()
+                .filter(|meta| {
+                    matches(table, &meta.table_name)
+                        && match database {
+                            Some(database) => matches(database, &meta.database_name),
+                            None => scope.session.database.as_ref().is_none_or(|database| {
+                                matches_setting(database, &meta.database_name)
+                            }),
                         }
+                        && schema.is_none_or(|schema| matches(schema, &meta.schema_name))
+                })
+                .collect::<Vec<&FullColumn>>();
+        let found = match schema {
+            None if !scope.session.search_path.is_empty() => {
+                scope.session.search_path.iter().find_map(|schema| {
+                    tables
+                        .iter()
+                        .find(|meta| matches_setting(schema, &meta.schema_name))
+                })
+            }
+            _ => tables.first(),
+        };
+        let Some(found) = found else {
+            return Vec::new();
+        };
+        tables
+            .iter()
+            .filter(|meta| {
+                meta.database_name == found.database_name && meta.schema_name == found.schema_name
+            })
+            .copied()
+            .collect()
+    }
+    /// The columns a relation in a FROM clause exposes, each with its own
+    /// lineage. A derived table exposes the output columns of its query.
+    fn relation_columns(
+        &self,
+        scope: &Scope,
+        relation: &TableFactor,
+    ) -> Result<Vec<ColumnLineage>, LineageError> {
+        if let Some((_, columns)) = scope.relations.iter().find(|(known, _)| known == relation) {
+            return Ok(columns.clone());
+        }
+        let (mut columns, alias) = match relation {
+            TableFactor::Table { name, alias, .. } => {
+                let cte = match name.0.as_slice() {
+                    [cte_name] => self.cte_columns(scope, cte_name),
+                    _ => None,
+                };
+                let columns = match cte {
+                    Some(cte) => cte.clone(),
+                    None => {
+                        let columns = self
+                            .catalog_columns(scope, name)
+                            .into_iter()
+                            .map(ColumnLineage::from)
+                            .collect::<Vec<ColumnLineage>>();
+                        if columns.is_empty() {
+                            return Err(scope.unknown_table(name));
+                        }
+                        columns
                     }
-                    TableFactor::Derived { subquery, .. } => {
-                        let expr = *subquery.body.clone();
-                        let needle = self.search_for_columns_in_expr(
-                            expr,
-                            column_name.clone(),
-                            table_name.clone(),
-                        );
-                        if needle.is_some() {
-                            return needle;
+                };
+                (columns, alias)
+            }
+            TableFactor::Derived {
+                subquery, alias, ..
+            } => {
+                let mut columns = Vec::new();
+                self.traverse_query(
+                    &Self::derived_scope(scope, relation),
+                    subquery,
+                    &mut columns,
+                )?;
+                (columns, alias)
+            }
+            relation => return Err(scope.unsupported(relation)),
+        };
+        // `AS name (a, b)` renames the columns by position.
+        if let Some(alias) = alias {
+            for (column, name) in columns.iter_mut().zip(&alias.columns) {
+                Self::rename_column(column, self.ident(name));
+            }
+        }
+        Ok(columns)
+    }
+    /// `scope` with the FROM clause `from`, with the columns of each of its
+    /// relations resolved once, in order, rather than on every column
+    /// looked up in them. A relation that can't be resolved is an error
+    /// even if no column is looked up in it.
+    fn resolve_from(
+        &self,
+        scope: &Scope,
+        from: Vec<TableWithJoins>,
+    ) -> Result<Scope, LineageError> {
+        let mut scope = scope.with_from(from);
+        let relations = Self::from_relations(&scope.from)
+            .into_iter()
+            .cloned()
+            .collect::<Vec<TableFactor>>();
+        for relation in relations {
+            let columns = self.relation_columns(&scope, &relation)?;
+            scope.relations.push((relation, columns));
+        }
+        Ok(scope)
+    }
+    /// The scope the derived table `relation` of the FROM clause of `scope`
+    /// is resolved in. A `LATERAL` derived table, or one that is `CROSS
+    /// APPLY`'d or `OUTER APPLY`'d, can reference the relations before it
+    /// in the FROM clause like a correlated subquery can reference the
+    /// outer query. Other derived tables only see the queries around the
+    /// one `scope` resolves.
+    fn derived_scope(scope: &Scope, relation: &TableFactor) -> Scope {
+        // The relations before it are already resolved.
+        let lateral_scope = |preceding: Vec<TableWithJoins>| Scope {
+            outer: Some(Box::new(Scope {
+                from: preceding,
+                ..scope.clone()
+            })),
+            ..scope.clone()
+        };
+        let is_lateral = matches!(relation, TableFactor::Derived { lateral: true, .. });
+        let mut preceding = Vec::new();
+        for TableWithJoins {
+            relation: first,
+            joins,
+        } in &scope.from
+        {
+            if first == relation {
+                return if is_lateral {
+                    lateral_scope(preceding)
+                } else {
+                    scope.clone()
+                };
+            }
+            let mut table = TableWithJoins {
+                relation: first.clone(),
+                joins: Vec::new(),
+            };
+            for join in joins {
+                if &join.relation == relation {
+                    let is_apply = matches!(
+                        join.join_operator,
+                        JoinOperator::CrossApply | JoinOperator::OuterApply
+                    );
+                    if !is_lateral && !is_apply {
+                        return scope.clone();
+                    }
+                    preceding.push(table);
+                    return lateral_scope(preceding);
+                }
+                table.joins.push(join.clone());
+            }
+            preceding.push(table);
+        }
+        scope.clone()
+    }
+    /// The columns of the CTE in `scope` that `name` refers to, if any.
+    fn cte_columns<'a>(&self, scope: &'a Scope, name: &Ident) -> Option<&'a Vec<ColumnLineage>> {
+        scope
+            .ctes
+            .iter()
+            .find(|(cte_name, _)| self.dialect.identifier_matches(name, cte_name))
+            .map(|(_, columns)| columns)
+    }
+    /// Makes the CTE `name` visible in `scope`, hiding any CTE of an outer
+    /// query the name refers to as well.
+    fn define_cte(&self, scope: &mut Scope, name: &Ident, columns: Vec<ColumnLineage>) {
+        scope
+            .ctes
+            .retain(|cte_name, _| !self.dialect.identifier_matches(name, cte_name));
+        scope.ctes.insert(self.ident(name), columns);
+    }
+    /// Whether `qualifier`, as in `qualifier.column` or `qualifier.*`,
+    /// refers to `relation`, by its alias if it has one or else by its
+    /// table name.
+    fn relation_matches(&self, relation: &TableFactor, qualifier: &ObjectName) -> bool {
+        match relation {
+            TableFactor::Table {
+                alias: Some(alias), ..
+            }
+            | TableFactor::Derived {
+                alias: Some(alias), ..
+            } => matches!(
+                qualifier.0.as_slice(),
+                [qualifier] if self.dialect.identifiers_match(qualifier, &alias.name)
+            ),
+            TableFactor::Table { name, .. } => {
+                let name = self.dialect.object_name_parts(name);
+                let qualifier = self.dialect.object_name_parts(qualifier);
+                name.len() >= qualifier.len()
+                    && name
+                        .iter()
+                        .rev()
+                        .zip(qualifier.iter().rev())
+                        .all(|(name, qualifier)| self.dialect.identifiers_match(name, qualifier))
+            }
+            _ => false,
+        }
+    }
+    /// Adds every column of `relation` to `columns`, applying the
+    /// `EXCEPT`/`EXCLUDE`/`RENAME`/`REPLACE` options of the wildcard.
+    fn expand_wildcard(
+        &self,
+        columns: &mut Vec<ColumnLineage>,
+        scope: &Scope,
+        relation: &TableFactor,
+        options: &WildcardAdditionalOptions,
+    ) -> Result<(), LineageError> {
+        let mut excluded = Vec::new();
+        if let Some(except) = &options.opt_except {
+            excluded.push(&except.first_element);
+            excluded.extend(&except.additional_elements);
+        }
+        match &options.opt_exclude {
+            Some(ExcludeSelectItem::Single(id)) => excluded.push(id),
+            Some(ExcludeSelectItem::Multiple(ids)) => excluded.extend(ids),
+            None => {}
+        }
+        let renamed = match &options.opt_rename {
+            Some(RenameSelectItem::Single(rename)) => vec![rename],
+            Some(RenameSelectItem::Multiple(renames)) => renames.iter().collect(),
+            None => Vec::new(),
+        };
+        let replaced = options
+            .opt_replace
+            .as_ref()
+            .map(|replace| replace.items.iter().collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        for column in self.relation_columns(scope, relation)? {
+            if excluded
+                .iter()
+                .any(|id| self.dialect.identifier_matches(id, &column.name))
+            {
+                continue;
+            }
+            let name = renamed
+                .iter()
+                .find(|rename| self.dialect.identifier_matches(&rename.ident, &column.name))
+                .map_or(column.name.clone(), |rename| self.ident(&rename.alias));
+
+            if let Some(replace) = replaced.iter().find(|replace| {
+                self.dialect
+                    .identifier_matches(&replace.column_name, &column.name)
+            }) {
+                columns.push(self.search_for_expr(scope, name, &replace.expr)?);
+                continue;
+            }
+            let mut column = column;
+            Self::rename_column(&mut column, name);
+            columns.push(column);
+        }
+        Ok(())
+    }
+    /// Adds every column of the relations in `from` to `columns`, as `*`
+    /// does. A column a join is `USING`, or that both sides of a `NATURAL`
+    /// join have, is a single column coalesced from both sides; any other
+    /// columns of the same name stay apart.
+    fn expand_joins(
+        &self,
+        columns: &mut Vec<ColumnLineage>,
+        scope: &Scope,
+        from: &[TableWithJoins],
+        options: &WildcardAdditionalOptions,
+    ) -> Result<(), LineageError> {
+        for TableWithJoins { relation, joins } in from {
+            let mut joined = Vec::new();
+            self.expand_factor(&mut joined, scope, relation, options)?;
+            for join in joins {
+                let mut found = Vec::new();
+                self.expand_factor(&mut found, scope, &join.relation, options)?;
+                let constraint = Self::join_constraint(&join.join_operator);
+                for column in found {
+                    let coalesced = match constraint {
+                        Some(JoinConstraint::Using(ids)) => ids
+                            .iter()
+                            .any(|id| self.dialect.identifier_matches(id, &column.name)),
+                        Some(JoinConstraint::Natural) => true,
+                        _ => false,
+                    };
+                    match joined.iter_mut().find(|v| v.name == column.name) {
+                        Some(left) if coalesced => {
+                            Self::merge_column(left, column);
                         }
+                        _ => joined.push(column),
                     }
-                    val => {
-                        println!("Skipping this relation: {val:?}");
+                }
+            }
+            columns.extend(joined);
+        }
+        Ok(())
+    }
+    /// Adds every column of `relation`, or of the relations it joins, to
+    /// `columns`.
+    fn expand_factor(
+        &self,
+        columns: &mut Vec<ColumnLineage>,
+        scope: &Scope,
+        relation: &TableFactor,
+        options: &WildcardAdditionalOptions,
+    ) -> Result<(), LineageError> {
+        match relation {
+            TableFactor::NestedJoin {
+                table_with_joins, ..
+            } => self.expand_joins(columns, scope, slice::from_ref(table_with_joins), options),
+            relation => self.expand_wildcard(columns, scope, relation, options),
+        }
+    }
+    /// The lineage of the output column `name` computed by `expr`: every
+    /// column `expr` reads from is an upstream of it.
+    fn search_for_expr(
+        &self,
+        scope: &Scope,
+        name: String,
+        expr: &Expr,
+    ) -> Result<ColumnLineage, LineageError> {
+        let mut column = ColumnLineage {
+            name: name.clone(),
+            upstream: Vec::new(),
+            ambiguous: false,
+            indirect: Vec::new(),
+        };
+        for (reference, transformation) in transformed_references(expr, &|function, position| {
+            self.function_transformation(function, position)
+        }) {
+            let found = match reference {
+                Reference::Column(ids) => {
+                    let Some((column_name, qualifier)) = Self::split_reference(&ids) else {
                         continue;
+                    };
+                    self.search_for_columns_in_tables(scope, column_name, qualifier)?
+                        .into_iter()
+                        .collect()
+                }
+                // A scalar subquery passes on the lineage of its column.
+                Reference::Subquery(query) => {
+                    let mut subquery_columns = Vec::new();
+                    self.traverse_query(&scope.subquery(), query, &mut subquery_columns)?;
+                    subquery_columns
+                }
+            };
+            for found in found {
+                let mut found = ColumnLineage {
+                    upstream: found
+                        .upstream
+                        .into_iter()
+                        .map(|(upstream, known)| (upstream, known.max(transformation)))
+                        .collect(),
+                    ..found
+                };
+                Self::rename_column(&mut found, name.clone());
+                Self::merge_column(&mut column, found);
+            }
+        }
+        let mut indirect = Vec::new();
+        for expr in influencing_exprs(expr, &scope.windows) {
+            indirect.extend(self.influencing_columns(scope, expr)?);
+        }
+        // `EXISTS (...)` and `x IN (...)` decide the value without making it
+        // up.
+        for query in filtering_subqueries(expr) {
+            indirect.extend(self.subquery_columns(scope, query)?);
+        }
+        Self::add_indirect(&mut column, indirect);
+        Ok(column)
+    }
+
+    /// Splits a column reference into the column name and the qualifier
+    /// it was written with, if any.
+    fn split_reference(ids: &[Ident]) -> Option<(Ident, Option<ObjectName>)> {
+        match ids {
+            [column_name] => Some((column_name.clone(), None)),
+            [qualifier @ .., column_name] => {
+                Some((column_name.clone(), Some(ObjectName(qualifier.to_vec()))))
+            }
+            [] => None,
+        }
+    }
+
+    /// Every upstream column the columns `expr` references, or the windows
+    /// of its functions, read from, directly or indirectly.
+    fn influencing_columns(&self, scope: &Scope, expr: &Expr) -> Result<Vec<String>, LineageError> {
+        let mut out = Vec::new();
+        for ids in column_references(expr) {
+            let Some((column_name, qualifier)) = Self::split_reference(&ids) else {
+                continue;
+            };
+            if let Some(found) = self.search_for_columns_in_tables(scope, column_name, qualifier)? {
+                out.extend(found.upstream.into_iter().map(|(upstream, _)| upstream));
+                out.extend(found.indirect);
+            }
+        }
+        // The windows of a filter like `QUALIFY row_number() over (...) = 1`
+        // decide which rows pass it too.
+        for expr in influencing_exprs(expr, &scope.windows) {
+            out.extend(self.influencing_columns(scope, expr)?);
+        }
+        for query in subqueries(expr) {
+            out.extend(self.subquery_columns(scope, query)?);
+        }
+        Ok(out)
+    }
+
+    /// Every upstream column the columns of `query`, a subquery of an
+    /// expression in `scope`, read from, directly or indirectly.
+    fn subquery_columns(&self, scope: &Scope, query: &Query) -> Result<Vec<String>, LineageError> {
+        let mut columns = Vec::new();
+        self.traverse_query(&scope.subquery(), query, &mut columns)?;
+        Ok(columns
+            .into_iter()
+            .flat_map(|column| {
+                let upstream = column.upstream.into_iter().map(|(upstream, _)| upstream);
+                upstream.chain(column.indirect)
+            })
+            .collect())
+    }
+
+    /// The expressions of a SELECT that decide which rows it returns
+    /// rather than their values: its `WHERE`, join conditions, `GROUP
+    /// BY`, `HAVING` and `QUALIFY`.
+    fn select_filters(select: &Select) -> Vec<Expr> {
+        let mut filters = Self::join_conditions(&select.from);
+        filters.extend(select.selection.clone());
+        if let GroupByExpr::Expressions(exprs) = &select.group_by {
+            filters.extend(exprs.iter().cloned());
+        }
+        filters.extend(select.having.clone());
+        filters.extend(select.qualify.clone());
+        filters
+    }
+
+    /// The `ON` conditions and `USING` columns of the joins in `from`.
+    fn join_conditions(from: &[TableWithJoins]) -> Vec<Expr> {
+        let mut conditions = Vec::new();
+        for TableWithJoins { relation, joins } in from {
+            for relation in iter::once(relation).chain(joins.iter().map(|join| &join.relation)) {
+                if let TableFactor::NestedJoin {
+                    table_with_joins, ..
+                } = relation
+                {
+                    conditions.extend(Self::join_conditions(slice::from_ref(table_with_joins)));
+                }
+            }
+            for join in joins {
+                match Self::join_constraint(&join.join_operator) {
+                    Some(JoinConstraint::On(expr)) => conditions.push(expr.clone()),
+                    Some(JoinConstraint::Using(ids)) => {
+                        conditions.extend(ids.iter().cloned().map(Expr::Identifier))
                     }
+                    _ => {}
                 }
             }
         }
-        needle
+        conditions
     }
-    fn search_for_columns_in_expr(
+
+    /// Resolves the output columns of `query`, making its common table
+    /// expressions visible to its body.
+    fn traverse_query(
         &self,
-        set_expr: SetExpr,
-        column_name: String,
-        table_name: Option<String>,
-    ) -> Option<String> {
-        match set_expr {
-            SetExpr::Select(select) => {
-                return self.search_for_columns_in_tables(select.from, column_name, table_name);
+        scope: &Scope,
+        query: &Query,
+        columns: &mut Vec<ColumnLineage>,
+    ) -> Result<(), LineageError> {
+        let mut scope = scope.clone();
+        if let Some(with) = &query.with {
+            for cte in &with.cte_tables {
+                let cte_columns = if with.recursive {
+                    self.traverse_recursive_cte(&scope, cte)?
+                } else {
+                    self.traverse_cte(&scope, cte)?
+                };
+                self.define_cte(&mut scope, &cte.alias.name, cte_columns);
             }
-            SetExpr::Query(_) => todo!(),
-            SetExpr::SetOperation { left, right, .. } => {
-                let out = self.search_for_columns_in_expr(
-                    *left.clone(),
-                    column_name.clone(),
-                    table_name.clone(),
-                );
-                if out.is_some() {
-                    return out;
-                }
-                let out = self.search_for_columns_in_expr(*right.clone(), column_name, table_name);
-                if out.is_some() {
-                    return out;
+        }
+        self.traverse_set_expr(&scope, *query.body.clone(), columns)
+    }
+
+    /// Resolves `INSERT INTO table_name (target_columns) source`, returning
+    /// the full name of the table. The n-th column of the source goes into
+    /// the n-th column of the table, named by the column list, or else by
+    /// the columns of the table in the catalog, or else by the source.
+    fn traverse_insert(
+        &self,
+        scope: &Scope,
+        table_name: &ObjectName,
+        target_columns: &[Ident],
+        source: &Query,
+        columns: &mut Vec<ColumnLineage>,
+    ) -> Result<String, LineageError> {
+        let mut source_columns = Vec::new();
+        self.traverse_query(scope, source, &mut source_columns)?;
+        let (target, catalog) = self.target_table(scope, table_name);
+        let names = if !target_columns.is_empty() {
+            target_columns
+                .iter()
+                .map(|column| self.target_column(&catalog, column))
+                .collect()
+        } else if !catalog.is_empty() {
+            catalog
+                .iter()
+                .map(|meta| meta.column_name.clone())
+                .collect()
+        } else {
+            source_columns
+                .iter()
+                .map(|v| v.name.clone())
+                .collect::<Vec<String>>()
+        };
+        // A column list has to name exactly as many columns as the source
+        // returns. Without one, the columns of the table the source leaves
+        // out get their defaults.
+        if source_columns.len() > names.len()
+            || !target_columns.is_empty() && source_columns.len() < names.len()
+        {
+            return Err(scope.column_count_mismatch(target, names.len(), source_columns.len()));
+        }
+        for (mut column, name) in source_columns.into_iter().zip(names) {
+            Self::rename_column(&mut column, name);
+            columns.push(column);
+        }
+        Ok(target)
+    }
+
+    /// Resolves `MERGE INTO table USING source ON on`, returning the full
+    /// name of the table. Every `WHEN` clause that writes to the table adds
+    /// the lineage of the columns it writes to `clauses`. The `ON`
+    /// condition, and the condition of each clause, are indirect lineage of
+    /// the columns. A `WHEN MATCHED` clause sees the target row joined to
+    /// the source row, while a `WHEN NOT MATCHED` clause has no target row
+    /// and only sees the source row.
+    fn traverse_merge(
+        &self,
+        scope: &Scope,
+        table: &TableFactor,
+        source: &TableFactor,
+        on: &Expr,
+        merge_clauses: &[MergeClause],
+        clauses: &mut Vec<ClauseLineage>,
+    ) -> Result<String, LineageError> {
+        let TableFactor::Table { name, .. } = table else {
+            return Err(scope.unsupported(table));
+        };
+        let matched_scope = self.resolve_from(
+            scope,
+            vec![TableWithJoins {
+                relation: table.clone(),
+                joins: vec![Join {
+                    relation: source.clone(),
+                    join_operator: JoinOperator::Inner(JoinConstraint::On(on.clone())),
+                }],
+            }],
+        )?;
+        let not_matched_scope = self.resolve_from(
+            scope,
+            vec![TableWithJoins {
+                relation: source.clone(),
+                joins: Vec::new(),
+            }],
+        )?;
+        let (target, catalog) = self.target_table(scope, name);
+        let on = self.influencing_columns(&matched_scope, on)?;
+
+        for clause in merge_clauses {
+            let (kind, scope, predicate, assignments) = match clause {
+                MergeClause::MatchedUpdate {
+                    predicate,
+                    assignments,
+                } => (
+                    ClauseKind::MatchedUpdate,
+                    &matched_scope,
+                    predicate,
+                    assignments
+                        .iter()
+                        .filter_map(|assignment| {
+                            let column = assignment.id.last()?;
+                            Some((self.target_column(&catalog, column), &assignment.value))
+                        })
+                        .collect::<Vec<(String, &Expr)>>(),
+                ),
+                MergeClause::NotMatched {
+                    predicate,
+                    columns: insert_columns,
+                    values,
+                } => {
+                    // Without a column list, the values go into the columns
+                    // of the table by position.
+                    let names = if insert_columns.is_empty() {
+                        catalog
+                            .iter()
+                            .map(|meta| meta.column_name.clone())
+                            .collect()
+                    } else {
+                        insert_columns
+                            .iter()
+                            .map(|column| self.target_column(&catalog, column))
+                            .collect::<Vec<String>>()
+                    };
+                    // Each row lines up with the columns like the source
+                    // of an `INSERT` does.
+                    for row in &values.rows {
+                        if row.len() > names.len()
+                            || !insert_columns.is_empty() && row.len() < names.len()
+                        {
+                            return Err(scope.column_count_mismatch(
+                                target,
+                                names.len(),
+                                row.len(),
+                            ));
+                        }
+                    }
+                    (
+                        ClauseKind::NotMatchedInsert,
+                        &not_matched_scope,
+                        predicate,
+                        values
+                            .rows
+                            .iter()
+                            .flat_map(|row| names.iter().cloned().zip(row))
+                            .collect(),
+                    )
                 }
+                MergeClause::MatchedDelete(_) => continue,
+            };
+            let mut indirect = on.clone();
+            if let Some(predicate) = predicate {
+                indirect.extend(self.influencing_columns(scope, predicate)?);
             }
-            SetExpr::Values(_) => todo!(),
-            SetExpr::Insert(_) => todo!(),
-            SetExpr::Update(_) => todo!(),
-            SetExpr::Table(_) => todo!(),
+            let mut clause_columns = Vec::new();
+            for (name, expr) in assignments {
+                Self::add_column(
+                    &mut clause_columns,
+                    self.search_for_expr(scope, name, expr)?,
+                );
+            }
+            for column in &mut clause_columns {
+                Self::add_indirect(column, indirect.clone());
+            }
+            clauses.push(ClauseLineage {
+                kind,
+                predicate: predicate.as_ref().map(ToString::to_string),
+                columns: clause_columns,
+            });
         }
-        None
+        Ok(target)
     }
-    pub fn search_for_col_and_add(
+    /// Resolves `UPDATE table SET assignments FROM from WHERE selection`,
+    /// returning the full name of the table. The tables of `FROM` are
+    /// visible to the assignments as if they were joined to the table, and
+    /// the `WHERE` condition and join conditions are indirect lineage of
+    /// every assigned column.
+    fn traverse_update(
+        &self,
+        scope: &Scope,
+        table: &TableWithJoins,
+        assignments: &[Assignment],
+        from: Option<TableWithJoins>,
+        selection: Option<&Expr>,
+        columns: &mut Vec<ColumnLineage>,
+    ) -> Result<String, LineageError> {
+        let TableFactor::Table { name, .. } = &table.relation else {
+            return Err(scope.unsupported(&table.relation));
+        };
+        let from = iter::once(table.clone()).chain(from).collect::<Vec<_>>();
+        let (target, catalog) = self.target_table(scope, name);
+        let mut filters = Self::join_conditions(&from);
+        filters.extend(selection.cloned());
+        let scope = self.resolve_from(scope, from)?;
+        let mut indirect = Vec::new();
+        for expr in &filters {
+            indirect.extend(self.influencing_columns(&scope, expr)?);
+        }
+
+        let mut update_columns = Vec::new();
+        for Assignment { id, value } in assignments {
+            let Some(column) = id.last() else {
+                continue;
+            };
+            let name = self.target_column(&catalog, column);
+            update_columns.push(self.search_for_expr(&scope, name, value)?);
+        }
+        for mut column in update_columns {
+            Self::add_indirect(&mut column, indirect.clone());
+            Self::add_column(columns, column);
+        }
+        Ok(target)
+    }
+    /// The full name of the table `name` a statement writes to, along with
+    /// its columns in the catalog.
+    fn target_table(&self, scope: &Scope, name: &ObjectName) -> (String, Vec<&FullColumn>) {
+        let catalog = self.catalog_columns(scope, name);
+        let target = match catalog.first() {
+            Some(meta) => meta.table_full_name(),
+            None => self.qualify_table(scope, name).table_full_name(),
+        };
+        (target, catalog)
+    }
+    /// The name of the column `column` of a table a statement writes to,
+    /// spelled the way the catalog does if the table is in it.
+    fn target_column(&self, catalog: &[&FullColumn], column: &Ident) -> String {
+        catalog
+            .iter()
+            .find(|meta| self.dialect.identifier_matches(column, &meta.column_name))
+            .map_or_else(|| self.ident(column), |meta| meta.column_name.clone())
+    }
+    /// Resolves `CREATE TABLE name (column_names) AS query` or `CREATE VIEW
+    /// name (column_names) AS query`, returning the full name of the new
+    /// table. The new table replaces any table of that name in the catalog,
+    /// so the statements after it can read from it.
+    fn traverse_create(
         &mut self,
-        from: Vec<TableWithJoins>,
-        column_name: String,
-        alias: Option<String>,
-        table_name: Option<String>,
-        opaque: bool,
-    ) {
-        if let Some(full_identifier) =
-            self.search_for_columns_in_tables(from, column_name.clone(), table_name.clone())
-        {
-            if let Some(alias) = alias {
-                self.add_dependency(alias, full_identifier, opaque);
+        scope: &Scope,
+        name: &ObjectName,
+        column_names: &[Ident],
+        query: Option<&Query>,
+        columns: &mut Vec<ColumnLineage>,
+    ) -> Result<String, LineageError> {
+        let column_names = column_names
+            .iter()
+            .map(|v| self.ident(v))
+            .collect::<Vec<String>>();
+        if let Some(query) = query {
+            self.traverse_query(scope, query, columns)?;
+            // `CREATE TABLE name (a, b) AS ...` renames the columns by position.
+            for (column, name) in columns.iter_mut().zip(&column_names) {
+                Self::rename_column(column, name.clone());
+            }
+        }
+        let table = self.qualify_table(scope, name);
+        self.metadata
+            .retain(|meta| meta.table_full_name() != table.table_full_name());
+        let created = match query {
+            Some(_) => columns.iter().map(|v| v.name.clone()).collect(),
+            None => column_names,
+        };
+        self.metadata
+            .extend(created.into_iter().map(|column_name| FullColumn {
+                column_name,
+                ..table.clone()
+            }));
+        Ok(table.table_full_name())
+    }
+    /// The table `name` refers to, as a column without a name, with the
+    /// database and schema the name leaves out taken from the current
+    /// database and the first schema of the search path.
+    fn qualify_table(&self, scope: &Scope, name: &ObjectName) -> FullColumn {
+        let parts = self.dialect.object_name_parts(name);
+        let mut parts = parts.iter().rev().map(|v| self.ident(v));
+        let table_name = parts.next().unwrap_or_default();
+        let schema_name = parts
+            .next()
+            .or_else(|| scope.session.search_path.first().cloned())
+            .unwrap_or_default();
+        let database_name = parts
+            .next()
+            .or_else(|| scope.session.database.clone())
+            .unwrap_or_default();
+        FullColumn {
+            database_name,
+            schema_name,
+            table_name,
+            column_name: String::new(),
+        }
+    }
+
+    fn traverse_cte(&self, scope: &Scope, cte: &Cte) -> Result<Vec<ColumnLineage>, LineageError> {
+        let mut columns = Vec::new();
+        self.traverse_query(scope, &cte.query, &mut columns)?;
+        // `WITH name (a, b) AS (...)` renames the columns by position.
+        for (column, alias) in columns.iter_mut().zip(&cte.alias.columns) {
+            Self::rename_column(column, self.ident(alias));
+        }
+        Ok(columns)
+    }
+
+    /// Resolves a CTE of a `WITH RECURSIVE` clause. The CTE is resolved
+    /// over and over with the lineage found so far visible under its own
+    /// name, until no new upstream columns turn up.
+    fn traverse_recursive_cte(
+        &self,
+        scope: &Scope,
+        cte: &Cte,
+    ) -> Result<Vec<ColumnLineage>, LineageError> {
+        let mut scope = scope.clone();
+        let mut columns: Vec<ColumnLineage> = Vec::new();
+        loop {
+            self.define_cte(&mut scope, &cte.alias.name, columns.clone());
+            let mut changed = false;
+            for (index, column) in self.traverse_cte(&scope, cte)?.into_iter().enumerate() {
+                if let Some(known) = columns.get_mut(index) {
+                    changed |= Self::merge_column(known, column);
+                } else {
+                    columns.push(column);
+                    changed = true;
+                }
+            }
+            if !changed {
+                return Ok(columns);
+            }
+        }
+    }
+
+    /// Adds the lineage of `other` to `column`, returning whether anything
+    /// was added.
+    fn merge_column(column: &mut ColumnLineage, other: ColumnLineage) -> bool {
+        let mut changed = Self::add_indirect(column, other.indirect);
+        for (upstream, transformation) in other.upstream {
+            if let Some((_, known)) = column.upstream.iter_mut().find(|(v, _)| *v == upstream) {
+                if transformation > *known {
+                    *known = transformation;
+                    changed = true;
+                }
             } else {
-                self.add_dependency(column_name, full_identifier, opaque);
+                column.upstream.push((upstream, transformation));
+                changed = true;
             }
         }
+        if other.ambiguous && !column.ambiguous {
+            column.ambiguous = true;
+            changed = true;
+        }
+        changed
     }
 
-    fn traverse_set_expr(&mut self, set_expr: SetExpr) {
+    /// Renames `column` to `name`, so the upstream columns it passed
+    /// through as is are now renamed. Only changing the case of the name,
+    /// as folding identifiers does, isn't a rename.
+    fn rename_column(column: &mut ColumnLineage, name: String) {
+        if column.name.to_lowercase() != name.to_lowercase() {
+            for (_, transformation) in &mut column.upstream {
+                *transformation = (*transformation).max(Transformation::Rename);
+            }
+        }
+        column.name = name;
+    }
+
+    /// Adds `indirect` to the indirect upstream columns of `column`,
+    /// returning whether anything was added.
+    fn add_indirect(column: &mut ColumnLineage, indirect: Vec<String>) -> bool {
+        let mut changed = false;
+        for upstream in indirect {
+            if !column.indirect.contains(&upstream) {
+                column.indirect.push(upstream);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn traverse_set_expr(
+        &self,
+        scope: &Scope,
+        set_expr: SetExpr,
+        columns: &mut Vec<ColumnLineage>,
+    ) -> Result<(), LineageError> {
         match set_expr {
             SetExpr::Select(select) => {
-                let mut output_col = String::new();
-                let mut dependency_alias = None;
-                let mut out_col_table: Option<String> = None;
                 let select = *select;
-                for projection in &select.projection {
+                let mut scope = self.resolve_from(scope, select.from.clone())?;
+                scope.windows = select.named_window.clone();
+                let mut select_columns = Vec::new();
+                let mut anonymous = 0;
+                for (position, projection) in select.projection.iter().enumerate() {
                     match projection {
-                        SelectItem::UnnamedExpr(Expr::Identifier(id)) => {
-                            output_col = id.value.clone();
+                        SelectItem::UnnamedExpr(expr) => {
+                            let name = self.expr_output_name(expr).unwrap_or_else(|| {
+                                anonymous += 1;
+                                self.dialect.unnamed_column(expr, position, anonymous - 1)
+                            });
+                            select_columns.push(self.search_for_expr(&scope, name, expr)?);
                         }
-                        SelectItem::UnnamedExpr(Expr::CompoundIdentifier(ids)) => {
-                            if ids.len() > 1 {
-                                out_col_table = ids.first().map(|v| v.value.clone());
-                            }
-                            let id = ids
-                                .last()
-                                .expect("Failed to get identifier from compound identifier");
-                            output_col = id.value.clone();
-                        }
-                        SelectItem::UnnamedExpr(_) => {}
                         SelectItem::ExprWithAlias { expr, alias } => {
-                            dependency_alias = Some(alias.value.clone());
-                            match expr {
-                                Expr::Function(function) => {
-                                    // TODO: Use this function name for opaque stuff.
-                                    let function_name = function
-                                        .name
-                                        .0
-                                        .clone()
-                                        .first()
-                                        .map(|v| v.value.clone().to_lowercase());
-
-                                    let opaque =
-                                        [Some("count".to_string()), Some("sum".to_string())]
-                                            .contains(&function_name);
-
-                                    for arg in &function.args {
-                                        if let FunctionArg::Unnamed(FunctionArgExpr::Expr(expr)) =
-                                            arg
-                                        {
-                                            let cols = Self::get_column_names_from_expr(expr);
-                                            for col in &cols {
-                                                // This col could be compound and have a table
-                                                // name in it. This is a bad way to do it and
-                                                // should be fixed.
-
-                                                let col = col.split('.').collect::<Vec<&str>>();
-                                                let column_name =
-                                                    col.last().expect("Failed to get any columns");
-
-                                                if col.len() > 1 {
-                                                    out_col_table = Some(col.first().expect("Failed to get table from column string").to_string());
-                                                }
-
-                                                self.search_for_col_and_add(
-                                                    select.from.clone(),
-                                                    column_name.to_string(),
-                                                    dependency_alias.clone(),
-                                                    out_col_table.clone(),
-                                                    opaque,
-                                                );
-                                            }
-                                        }
-                                    }
-                                }
-                                Expr::Identifier(id) => {
-                                    output_col = id.value.clone();
-                                }
-                                _ => unimplemented!("{expr:?} is not implemented"),
+                            select_columns.push(self.search_for_expr(
+                                &scope,
+                                self.ident(alias),
+                                expr,
+                            )?);
+                        }
+                        SelectItem::QualifiedWildcard(qualifier, options) => {
+                            let relations = Self::from_relations(&select.from)
+                                .into_iter()
+                                .filter(|relation| self.relation_matches(relation, qualifier))
+                                .collect::<Vec<&TableFactor>>();
+                            if relations.is_empty() {
+                                return Err(scope.unknown_table(qualifier));
+                            }
+                            for relation in relations {
+                                self.expand_wildcard(
+                                    &mut select_columns,
+                                    &scope,
+                                    relation,
+                                    options,
+                                )?;
                             }
                         }
-                        SelectItem::QualifiedWildcard(_, _) => {
-                            panic!("Wild card not supported")
+                        SelectItem::Wildcard(options) => {
+                            self.expand_joins(&mut select_columns, &scope, &select.from, options)?;
                         }
-                        SelectItem::Wildcard(_) => panic!("Wild card not supported"),
-                    }
-
-                    if !output_col.is_empty() {
-                        self.search_for_col_and_add(
-                            select.from.clone(),
-                            output_col.clone(),
-                            dependency_alias.clone(),
-                            out_col_table.clone(),
-                            false,
-                        );
                     }
                 }
+                // Whatever decides which rows the SELECT returns influences
+                // every one of its columns.
+                let mut indirect = Vec::new();
+                for expr in Self::select_filters(&select) {
+                    indirect.extend(self.influencing_columns(&scope, &expr)?);
+                }
+                for mut column in select_columns {
+                    Self::add_indirect(&mut column, indirect.clone());
+                    columns.push(column);
+                }
             }
             // This is for unions
             SetExpr::SetOperation { left, right, .. } => {
-                self.traverse_set_expr(*left);
-                self.traverse_set_expr(*right);
+                // The output columns are named by the left side, and the right
+                // side lines up with them by position.
+                let mut left_columns = Vec::new();
+                let mut right_columns = Vec::new();
+                self.traverse_set_expr(scope, *left, &mut left_columns)?;
+                self.traverse_set_expr(scope, *right, &mut right_columns)?;
+                for (left, mut right) in left_columns.iter_mut().zip(right_columns) {
+                    Self::rename_column(&mut right, left.name.clone());
+                    Self::merge_column(left, right);
+                }
+                columns.extend(left_columns);
             }
-            _ => unimplemented!(),
+            SetExpr::Query(query) => return self.traverse_query(scope, &query, columns),
+            set_expr => return Err(scope.unsupported(Self::set_expr_kind(&set_expr))),
         }
+        Ok(())
     }
 
-    pub fn traverse_statements(&mut self, statements: Vec<Statement>) {
-        for statement in &statements {
-            let body = if let Statement::Query(query) = statement {
-                *query.body.clone()
-            } else {
-                continue;
-            };
-            self.traverse_set_expr(body);
+    /// A short description of a kind of query body, for error messages.
+    fn set_expr_kind(set_expr: &SetExpr) -> &'static str {
+        match set_expr {
+            SetExpr::Select(_) => "SELECT",
+            SetExpr::Query(_) => "parenthesized query",
+            SetExpr::SetOperation { .. } => "set operation",
+            SetExpr::Values(_) => "VALUES",
+            SetExpr::Insert(_) => "INSERT",
+            SetExpr::Update(_) => "UPDATE",
+            SetExpr::Table(_) => "TABLE",
         }
     }
-    pub fn get_query_lineage(&mut self, sql: String) -> Result<(), Box<dyn std::error::Error>> {
-        use sqlparser::dialect::GenericDialect;
-        use sqlparser::parser::Parser;
-
-        let dialect = GenericDialect {};
 
-        let statements = Parser::parse_sql(&dialect, &sql)?;
-        self.traverse_statements(statements);
+    pub fn traverse_statements(
+        &mut self,
+        statements: Vec<SourceStatement>,
+    ) -> Vec<Result<StatementLineage, LineageError>> {
+        let mut session = self.session();
+        statements
+            .into_iter()
+            .map(|statement| self.traverse_statement(&mut session, statement))
+            .collect()
+    }
+    /// The current database and search path a run of statements starts
+    /// with.
+    fn session(&self) -> Session {
+        Session {
+            database: self.database.clone(),
+            search_path: self.search_path.clone(),
+        }
+    }
+    /// The lineage of a statement run in `session`, which `USE` and `SET
+    /// search_path` change for the statements after it.
+    fn traverse_statement(
+        &mut self,
+        session: &mut Session,
+        SourceStatement {
+            index,
+            statement,
+            span,
+            sql,
+        }: SourceStatement,
+    ) -> Result<StatementLineage, LineageError> {
+        let scope = Scope {
+            session: session.clone(),
+            ..Scope::new(index, span.start)
+        };
+        let mut columns = Vec::new();
+        let mut clauses = Vec::new();
+        let result = match statement {
+            Statement::Query(query) => self
+                .traverse_query(&scope, &query, &mut columns)
+                .map(|_| None),
+            Statement::Insert {
+                table_name,
+                columns: target_columns,
+                source: Some(source),
+                ..
+            } => self
+                .traverse_insert(&scope, &table_name, &target_columns, &source, &mut columns)
+                .map(Some),
+            Statement::CreateTable {
+                name,
+                columns: column_defs,
+                query,
+                ..
+            } => {
+                let column_names = column_defs
+                    .into_iter()
+                    .map(|column| column.name)
+                    .collect::<Vec<Ident>>();
+                self.traverse_create(&scope, &name, &column_names, query.as_deref(), &mut columns)
+                    .map(Some)
+            }
+            Statement::CreateView {
+                name,
+                columns: column_defs,
+                query,
+                ..
+            } => {
+                let column_names = column_defs
+                    .into_iter()
+                    .map(|column| column.name)
+                    .collect::<Vec<Ident>>();
+                self.traverse_create(&scope, &name, &column_names, Some(&query), &mut columns)
+                    .map(Some)
+            }
+            Statement::Merge {
+                table,
+                source,
+                on,
+                clauses: merge_clauses,
+                ..
+            } => self
+                .traverse_merge(&scope, &table, &source, &on, &merge_clauses, &mut clauses)
+                .map(|target| {
+                    // The columns of the statement are written by any
+                    // of its clauses.
+                    for clause in &clauses {
+                        for column in &clause.columns {
+                            Self::add_column(&mut columns, column.clone());
+                        }
+                    }
+                    Some(target)
+                }),
+            Statement::Update {
+                table,
+                assignments,
+                from,
+                selection,
+                ..
+            } => self
+                .traverse_update(
+                    &scope,
+                    &table,
+                    &assignments,
+                    from,
+                    selection.as_ref(),
+                    &mut columns,
+                )
+                .map(Some),
+            Statement::Use { db_name } => {
+                if self.dialect.use_selects_schema() {
+                    session.search_path = vec![self.ident(&db_name)];
+                } else {
+                    session.database = Some(self.ident(&db_name));
+                }
+                Ok(None)
+            }
+            Statement::SetVariable {
+                variable, value, ..
+            } if matches!(variable.0.as_slice(), [name] if name.value.eq_ignore_ascii_case("search_path")) =>
+            {
+                session.search_path = value
+                    .iter()
+                    .filter_map(|schema| match schema {
+                        Expr::Identifier(schema) => Some(self.ident(schema)),
+                        Expr::Value(Value::SingleQuotedString(schema)) => Some(schema.clone()),
+                        _ => None,
+                    })
+                    .collect();
+                Ok(None)
+            }
+            _ => Err(scope.unsupported(Self::statement_kind(&sql))),
+        };
+        let target = result?;
+        for column in &columns {
+            let name = match &target {
+                Some(target) => format!("{target}.{}", column.name),
+                None => column.name.clone(),
+            };
+            for (upstream, transformation) in &column.upstream {
+                self.add_dependency(name.clone(), upstream.clone(), *transformation);
+            }
+        }
+        Ok(StatementLineage {
+            index,
+            span,
+            sql,
+            target,
+            columns,
+            clauses,
+        })
+    }
+    /// The lineage of every statement in `sql`, failing on the first
+    /// statement whose lineage can't be worked out.
+    pub fn get_query_lineage(
+        &mut self,
+        sql: String,
+    ) -> Result<Vec<StatementLineage>, LineageError> {
+        self.collect_query_lineage(&sql).into_iter().collect()
+    }
+    /// The lineage of every statement in `sql`. A statement whose lineage
+    /// can't be worked out gets an error in its place, and doesn't stop the
+    /// statements after it from being analyzed.
+    pub fn collect_query_lineage(
+        &mut self,
+        sql: &str,
+    ) -> Vec<Result<StatementLineage, LineageError>> {
+        let dialect = self.dialect.parser_dialect();
 
-        Ok(())
+        let mut session = self.session();
+        let mut lineage = Vec::new();
+        for statement in Self::parse_statements(&*dialect, sql) {
+            match statement {
+                Ok(statement) => lineage.push(self.traverse_statement(&mut session, statement)),
+                Err(error) => lineage.push(Err(error)),
+            }
+        }
+        lineage
     }
-    fn get_column_names_from_expr(expr: &Expr) -> Vec<String> {
-        let mut out = Vec::new();
-        match expr {
-            Expr::BinaryOp { left, right, .. } => {
-                let left = Self::get_column_names_from_expr(left);
-                let right = Self::get_column_names_from_expr(right);
-                out.extend(left);
-                out.extend(right);
+    /// Parses `sql` one statement at a time so that each statement keeps
+    /// track of where it came from in the input. A statement that fails to
+    /// parse is skipped up to the next `;`.
+    fn parse_statements(
+        dialect: &dyn sqlparser::dialect::Dialect,
+        sql: &str,
+    ) -> Vec<Result<SourceStatement, LineageError>> {
+        let tokens = match Tokenizer::new(dialect, sql).tokenize_with_location() {
+            Ok(tokens) => tokens,
+            Err(error) => {
+                return vec![Err(LineageError::Parse {
+                    statement: 0,
+                    location: error.location,
+                    message: error.message,
+                })]
             }
-            Expr::Identifier(id) => {
-                out.push(id.value.clone());
+        };
+        let mut parser = Parser::new(dialect).with_tokens_with_locations(tokens);
+        let mut statements = Vec::new();
+        // Where the last statement ended, as a byte offset and a location,
+        // so finding the text of the next one doesn't scan the input from
+        // the top again.
+        let mut cursor = (0, Location { line: 1, column: 1 });
+        loop {
+            while parser.consume_token(&Token::SemiColon) {}
+            let start = parser.peek_token();
+            if start.token == Token::EOF {
+                break;
             }
-            Expr::CompoundIdentifier(ids) => {
-                let ids = ids.iter().map(|v| v.value.clone()).collect::<Vec<String>>();
-                let id = ids.join(".");
+            let index = statements.len();
+            let start_index = parser.index();
+            let statement = parser
+                .parse_statement()
+                .map_err(|error| (error.to_string(), None))
+                .and_then(|statement| {
+                    let next = parser.peek_token();
+                    if next.token != Token::SemiColon && next.token != Token::EOF {
+                        return Err((
+                            format!("Expected end of statement, found: {next}"),
+                            Some(next.location),
+                        ));
+                    }
+                    Ok(statement)
+                });
+            let statement = match statement {
+                Ok(statement) => statement,
+                Err((message, location)) => {
+                    // A statement that goes on past its end fails where it
+                    // should have ended. Otherwise the parser names the
+                    // token it failed at in the message, which may or may
+                    // not be consumed by then. Without it, the last token it
+                    // consumed is the best guess.
+                    let location = location
+                        .or_else(|| Self::error_location(&message))
+                        .unwrap_or_else(|| {
+                            if parser.index() > start_index {
+                                parser.prev_token();
+                                parser.next_token().location
+                            } else {
+                                start.location
+                            }
+                        });
+                    statements.push(Err(LineageError::Parse {
+                        statement: index,
+                        location: if location.line == 0 {
+                            start.location
+                        } else {
+                            location
+                        },
+                        message,
+                    }));
+                    loop {
+                        let token = parser.next_token();
+                        if token.token == Token::SemiColon || token.token == Token::EOF {
+                            break;
+                        }
+                    }
+                    continue;
+                }
+            };
+            // The token right after the statement, without skipping
+            // whitespace, is where the statement's text ends.
+            let end = parser.peek_token_no_skip();
+            let start_offset = Self::offset_of(sql, cursor, start.location);
+            let end_offset = if end.token == Token::EOF {
+                sql.len()
+            } else {
+                Self::offset_of(sql, (start_offset, start.location), end.location)
+            };
+            let text = sql[start_offset..end_offset].trim_end();
+            let end_offset = start_offset + text.len();
+            cursor = (
+                end_offset,
+                Self::location_at(sql, (start_offset, start.location), end_offset),
+            );
+            statements.push(Ok(SourceStatement {
+                index,
+                statement,
+                span: SourceSpan {
+                    start: start.location,
+                    end: cursor.1,
+                },
+                sql: text.to_string(),
+            }));
+        }
+        statements
+    }
+    /// The location a parser error message ends with, as in `Expected
+    /// an expression, found: EOF at Line: 1, Column 8`.
+    fn error_location(message: &str) -> Option<Location> {
+        let (_, location) = message.rsplit_once(" at Line: ")?;
+        let (line, column) = location.split_once(", Column ")?;
+        Some(Location {
+            line: line.parse().ok()?,
+            column: column.parse().ok()?,
+        })
+    }
 
-                out.push(id);
+    /// A short description of a kind of statement, for error messages.
+    fn statement_kind(sql: &str) -> String {
+        let keyword = sql.split_whitespace().next().unwrap_or_default();
+        format!("{} statement", keyword.to_uppercase())
+    }
+    /// Converts a tokenizer location into a byte offset into `sql`,
+    /// scanning from `from`, a byte offset no later than it along with its
+    /// location.
+    fn offset_of(sql: &str, from: (usize, Location), location: Location) -> usize {
+        let (start, mut current) = from;
+        for (offset, c) in sql[start..].char_indices() {
+            if current == location {
+                return start + offset;
+            }
+            if c == '\n' {
+                current.line += 1;
+                current.column = 1;
+            } else {
+                current.column += 1;
+            }
+        }
+        sql.len()
+    }
+    /// Converts a byte offset into `sql` into a tokenizer location,
+    /// scanning from `from`, a byte offset no later than it along with its
+    /// location.
+    fn location_at(sql: &str, from: (usize, Location), offset: usize) -> Location {
+        let (start, mut location) = from;
+        for c in sql[start..offset].chars() {
+            if c == '\n' {
+                location.line += 1;
+                location.column = 1;
+            } else {
+                location.column += 1;
             }
-            _ => unimplemented!(),
         }
-        out
+        location
+    }
+    /// The name of the output column `expr` produces when it isn't given an
+    /// alias.
+    fn expr_output_name(&self, expr: &Expr) -> Option<String> {
+        match expr {
+            Expr::Identifier(id) => Some(self.ident(id)),
+            Expr::CompoundIdentifier(ids) => ids.last().map(|v| self.ident(v)),
+            _ => None,
+        }
+    }
+    /// How a call to `function` transforms its argument at `position`, or
+    /// `None` if the argument doesn't carry lineage.
+    fn function_transformation(
+        &self,
+        function: &Function,
+        position: usize,
+    ) -> Option<Transformation> {
+        let function_name = function
+            .name
+            .0
+            .last()
+            .map(|v| v.value.to_lowercase())
+            .unwrap_or_default();
+        let definition = self.functions.get(&function_name);
+        if definition.is_some_and(|definition| !definition.carries_lineage(position)) {
+            return None;
+        }
+        if function.over.is_some() {
+            return Some(Transformation::Window);
+        }
+        Some(definition.map_or(Transformation::Arithmetic, |definition| {
+            definition.transformation
+        }))
     }
 }
 impl From<Vec<FullColumn>> for QueryAnalytics {
@@ -308,14 +1753,24 @@ impl From<Vec<FullColumn>> for QueryAnalytics {
         Self {
             metadata,
             dependency_map: HashMap::new(),
+            dialect: Dialect::default(),
+            permissive: false,
+            functions: default_functions(),
+            database: None,
+            search_path: Vec::new(),
         }
     }
 }
-impl From<HashMap<String, (Vec<String>, bool)>> for QueryAnalytics {
-    fn from(dependency_map: HashMap<String, (Vec<String>, bool)>) -> Self {
+impl From<HashMap<String, Vec<(String, Transformation)>>> for QueryAnalytics {
+    fn from(dependency_map: HashMap<String, Vec<(String, Transformation)>>) -> Self {
         Self {
             dependency_map,
             metadata: Vec::new(),
+            dialect: Dialect::default(),
+            permissive: false,
+            functions: default_functions(),
+            database: None,
+            search_path: Vec::new(),
         }
     }
 }
@@ -0,0 +1,172 @@
+use clap::ValueEnum;
+use serde::Deserialize;
+use sqlparser::ast::{Expr, Ident, ObjectName};
+use sqlparser::dialect::{
+    AnsiDialect, BigQueryDialect, ClickHouseDialect, DuckDbDialect, GenericDialect, HiveDialect,
+    MsSqlDialect, MySqlDialect, PostgreSqlDialect, RedshiftSqlDialect, SQLiteDialect,
+    SnowflakeDialect,
+};
+
+/// The SQL dialect the analyzed queries are written in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Dialect {
+    #[default]
+    Generic,
+    Ansi,
+    Postgres,
+    Redshift,
+    Snowflake,
+    #[value(name = "bigquery")]
+    BigQuery,
+    #[value(name = "mysql")]
+    MySql,
+    #[value(name = "mssql")]
+    MsSql,
+    Hive,
+    Spark,
+    #[value(name = "duckdb")]
+    DuckDb,
+    Sqlite,
+    #[value(name = "clickhouse")]
+    ClickHouse,
+}
+
+impl Dialect {
+    /// The sqlparser dialect queries in this dialect are parsed with.
+    pub fn parser_dialect(&self) -> Box<dyn sqlparser::dialect::Dialect> {
+        match self {
+            Dialect::Generic => Box::new(GenericDialect),
+            Dialect::Ansi => Box::new(AnsiDialect {}),
+            Dialect::Postgres => Box::new(PostgreSqlDialect {}),
+            Dialect::Redshift => Box::new(RedshiftSqlDialect {}),
+            Dialect::Snowflake => Box::new(SnowflakeDialect),
+            Dialect::BigQuery => Box::new(BigQueryDialect),
+            Dialect::MySql => Box::new(MySqlDialect {}),
+            Dialect::MsSql => Box::new(MsSqlDialect {}),
+            // Spark SQL is parsed like HiveQL, which it grew out of.
+            Dialect::Hive | Dialect::Spark => Box::new(HiveDialect {}),
+            Dialect::DuckDb => Box::new(DuckDbDialect),
+            Dialect::Sqlite => Box::new(SQLiteDialect {}),
+            Dialect::ClickHouse => Box::new(ClickHouseDialect {}),
+        }
+    }
+
+    /// The name `ident` refers to. Unquoted identifiers are folded to the
+    /// case the dialect stores names in, quoted ones are kept as they are.
+    pub fn fold_identifier(&self, ident: &Ident) -> String {
+        if ident.quote_style.is_some() {
+            return ident.value.clone();
+        }
+        match self {
+            Dialect::Postgres | Dialect::Redshift => ident.value.to_lowercase(),
+            Dialect::Ansi | Dialect::Snowflake => ident.value.to_uppercase(),
+            _ => ident.value.clone(),
+        }
+    }
+
+    /// Whether `ident` only matches names of the exact same case once it
+    /// is folded.
+    fn is_case_sensitive(&self, ident: &Ident) -> bool {
+        match self {
+            Dialect::Ansi | Dialect::Postgres | Dialect::Snowflake | Dialect::ClickHouse => true,
+            Dialect::Generic => ident.quote_style.is_some(),
+            Dialect::Redshift
+            | Dialect::BigQuery
+            | Dialect::MySql
+            | Dialect::MsSql
+            | Dialect::Hive
+            | Dialect::Spark
+            | Dialect::DuckDb
+            | Dialect::Sqlite => false,
+        }
+    }
+
+    /// Whether `ident` refers to a database, schema, table or column
+    /// stored under `name`.
+    pub fn identifier_matches(&self, ident: &Ident, name: &str) -> bool {
+        let folded = self.fold_identifier(ident);
+        if self.is_case_sensitive(ident) {
+            folded == name
+        } else {
+            folded.to_lowercase() == name.to_lowercase()
+        }
+    }
+
+    /// Whether two identifiers refer to the same name.
+    pub fn identifiers_match(&self, ident: &Ident, other: &Ident) -> bool {
+        self.identifier_matches(ident, &self.fold_identifier(other))
+            && self.identifier_matches(other, &self.fold_identifier(ident))
+    }
+
+    /// Whether `USE name` selects a schema rather than a database, in the
+    /// dialects that call their schemas databases.
+    pub fn use_selects_schema(&self) -> bool {
+        matches!(
+            self,
+            Dialect::MySql | Dialect::Hive | Dialect::Spark | Dialect::ClickHouse
+        )
+    }
+
+    /// The parts of an object name, from the database down. BigQuery lets a
+    /// single quoted identifier hold a whole path, as in
+    /// `` `project.dataset.table` ``.
+    pub fn object_name_parts(&self, name: &ObjectName) -> Vec<Ident> {
+        match self {
+            Dialect::BigQuery => name
+                .0
+                .iter()
+                .flat_map(|ident| match ident.quote_style {
+                    Some(quote) => ident
+                        .value
+                        .split('.')
+                        .map(|part| Ident::with_quote(quote, part))
+                        .collect(),
+                    None => vec![ident.clone()],
+                })
+                .collect(),
+            _ => name.0.clone(),
+        }
+    }
+
+    /// The name the target engine gives the output column of an expression
+    /// that doesn't have an alias. `position` is where the expression is in
+    /// the projection and `anonymous` how many columns without a name come
+    /// before it, both starting from 0.
+    pub fn unnamed_column(&self, expr: &Expr, position: usize, anonymous: usize) -> String {
+        match self {
+            Dialect::Generic
+            | Dialect::Ansi
+            | Dialect::MySql
+            | Dialect::MsSql
+            | Dialect::DuckDb
+            | Dialect::Sqlite
+            | Dialect::ClickHouse => expr.to_string(),
+            Dialect::Postgres | Dialect::Redshift => self
+                .postgres_column_name(expr)
+                .unwrap_or_else(|| "?column?".to_string()),
+            Dialect::Snowflake => expr.to_string().to_uppercase(),
+            Dialect::BigQuery => format!("f{anonymous}_"),
+            Dialect::Hive | Dialect::Spark => format!("_c{position}"),
+        }
+    }
+
+    /// Postgres names a column after the column, function or type an
+    /// expression is built around, see `FigureColname` in Postgres.
+    fn postgres_column_name(&self, expr: &Expr) -> Option<String> {
+        match expr {
+            Expr::Identifier(id) => Some(self.fold_identifier(id)),
+            Expr::CompoundIdentifier(ids) => ids.last().map(|v| self.fold_identifier(v)),
+            Expr::Cast {
+                expr, data_type, ..
+            } => self
+                .postgres_column_name(expr)
+                .or_else(|| Some(data_type.to_string().to_lowercase())),
+            Expr::Nested(expr) => self.postgres_column_name(expr),
+            Expr::Function(function) => function.name.0.last().map(|v| v.value.to_lowercase()),
+            Expr::Case { .. } => Some("case".to_string()),
+            Expr::Array(_) => Some("array".to_string()),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use sqlparser::tokenizer::Location;
+use std::fmt;
+
+/// Why the lineage of a statement couldn't be worked out.
+///
+/// Every error carries the index of the statement it happened in and a
+/// location in the input, so a bad statement can be reported and skipped
+/// without giving up on the rest of the input.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LineageError {
+    /// The statement isn't valid SQL for the dialect.
+    Parse {
+        statement: usize,
+        location: Location,
+        message: String,
+    },
+    /// The statement uses something lineage can't be worked out for.
+    Unsupported {
+        statement: usize,
+        location: Location,
+        construct: String,
+    },
+    /// An unqualified column name matches columns of more than one of the
+    /// relations it could come from.
+    AmbiguousColumn {
+        statement: usize,
+        location: Location,
+        column: String,
+        candidates: Vec<String>,
+    },
+    /// The statement reads from a table that is neither in the catalog nor
+    /// defined by the statement itself.
+    UnknownTable {
+        statement: usize,
+        location: Location,
+        table: String,
+    },
+    /// An `INSERT`, or the `INSERT` of a `MERGE`, names a different number
+    /// of columns than its source query or `VALUES` row has.
+    ColumnCountMismatch {
+        statement: usize,
+        location: Location,
+        target: String,
+        expected: usize,
+        found: usize,
+    },
+}
+
+impl LineageError {
+    /// The index of the statement the error happened in.
+    pub fn statement(&self) -> usize {
+        match self {
+            LineageError::Parse { statement, .. }
+            | LineageError::Unsupported { statement, .. }
+            | LineageError::AmbiguousColumn { statement, .. }
+            | LineageError::UnknownTable { statement, .. }
+            | LineageError::ColumnCountMismatch { statement, .. } => *statement,
+        }
+    }
+
+    /// Where in the input the error happened.
+    pub fn location(&self) -> Location {
+        match self {
+            LineageError::Parse { location, .. }
+            | LineageError::Unsupported { location, .. }
+            | LineageError::AmbiguousColumn { location, .. }
+            | LineageError::UnknownTable { location, .. }
+            | LineageError::ColumnCountMismatch { location, .. } => *location,
+        }
+    }
+}
+
+impl fmt::Display for LineageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let location = self.location();
+        write!(
+            f,
+            "statement {} ({}:{}): ",
+            self.statement(),
+            location.line,
+            location.column
+        )?;
+        match self {
+            LineageError::Parse { message, .. } => write!(f, "{message}"),
+            LineageError::Unsupported { construct, .. } => {
+                write!(f, "{construct} is not supported")
+            }
+            LineageError::AmbiguousColumn {
+                column, candidates, ..
+            } => write!(
+                f,
+                "column {column} is ambiguous, it could be any of {}",
+                candidates.join(", ")
+            ),
+            LineageError::UnknownTable { table, .. } => write!(f, "unknown table {table}"),
+            LineageError::ColumnCountMismatch {
+                target,
+                expected,
+                found,
+                ..
+            } => write!(
+                f,
+                "{found} columns are inserted into {expected} columns of {target}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LineageError {}
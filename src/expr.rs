@@ -0,0 +1,475 @@
+use sqlparser::ast::{
+    BinaryOperator, Expr, Function, FunctionArg, FunctionArgExpr, Ident, ListAggOnOverflow,
+    NamedWindowDefinition, Query, UnaryOperator, WindowFrameBound, WindowSpec, WindowType,
+};
+use std::iter;
+
+use crate::lineage::Transformation;
+
+/// Calls `visit` on `expr` and on every expression nested in it that
+/// contributes to its value.
+///
+/// Subqueries are not descended into, and neither are the `FILTER`, `OVER`
+/// and `ORDER BY` clauses of functions since they only decide which rows a
+/// function sees.
+pub fn visit_expr<'a>(expr: &'a Expr, visit: &mut impl FnMut(&'a Expr)) {
+    visit(expr);
+    for expr in sub_exprs(expr) {
+        visit_expr(expr, visit);
+    }
+}
+
+/// The expressions directly nested in `expr` that contribute to its value.
+fn sub_exprs(expr: &Expr) -> Vec<&Expr> {
+    let mut out: Vec<&Expr> = Vec::new();
+    match expr {
+        Expr::Identifier(_)
+        | Expr::CompoundIdentifier(_)
+        | Expr::Value(_)
+        | Expr::IntroducedString { .. }
+        | Expr::TypedString { .. }
+        | Expr::MatchAgainst { .. }
+        | Expr::Wildcard
+        | Expr::QualifiedWildcard(_)
+        | Expr::Exists { .. }
+        | Expr::Subquery(_)
+        | Expr::ArraySubquery(_) => {}
+        Expr::JsonAccess { left: expr, .. }
+        | Expr::CompositeAccess { expr, .. }
+        | Expr::IsFalse(expr)
+        | Expr::IsNotFalse(expr)
+        | Expr::IsTrue(expr)
+        | Expr::IsNotTrue(expr)
+        | Expr::IsNull(expr)
+        | Expr::IsNotNull(expr)
+        | Expr::IsUnknown(expr)
+        | Expr::IsNotUnknown(expr)
+        | Expr::InSubquery { expr, .. }
+        | Expr::UnaryOp { expr, .. }
+        | Expr::Convert { expr, .. }
+        | Expr::Cast { expr, .. }
+        | Expr::TryCast { expr, .. }
+        | Expr::SafeCast { expr, .. }
+        | Expr::AtTimeZone {
+            timestamp: expr, ..
+        }
+        | Expr::Extract { expr, .. }
+        | Expr::Ceil { expr, .. }
+        | Expr::Floor { expr, .. }
+        | Expr::Collate { expr, .. }
+        | Expr::Nested(expr)
+        | Expr::AggregateExpressionWithFilter { expr, .. }
+        | Expr::Named { expr, .. }
+        | Expr::OuterJoin(expr) => out.push(expr),
+        Expr::Interval(interval) => out.push(&interval.value),
+        Expr::IsDistinctFrom(left, right)
+        | Expr::IsNotDistinctFrom(left, right)
+        | Expr::BinaryOp { left, right, .. }
+        | Expr::AnyOp { left, right, .. }
+        | Expr::AllOp { left, right, .. }
+        | Expr::InUnnest {
+            expr: left,
+            array_expr: right,
+            ..
+        }
+        | Expr::Like {
+            expr: left,
+            pattern: right,
+            ..
+        }
+        | Expr::ILike {
+            expr: left,
+            pattern: right,
+            ..
+        }
+        | Expr::SimilarTo {
+            expr: left,
+            pattern: right,
+            ..
+        }
+        | Expr::RLike {
+            expr: left,
+            pattern: right,
+            ..
+        }
+        | Expr::Position {
+            expr: left,
+            r#in: right,
+        } => {
+            out.push(left);
+            out.push(right);
+        }
+        Expr::InList { expr, list, .. } => {
+            out.push(expr);
+            for expr in list {
+                out.push(expr);
+            }
+        }
+        Expr::Between {
+            expr, low, high, ..
+        } => {
+            out.push(expr);
+            out.push(low);
+            out.push(high);
+        }
+        Expr::Substring {
+            expr,
+            substring_from,
+            substring_for,
+            ..
+        } => {
+            out.push(expr);
+            for expr in substring_from.iter().chain(substring_for) {
+                out.push(expr);
+            }
+        }
+        Expr::Trim {
+            expr,
+            trim_what,
+            trim_characters,
+            ..
+        } => {
+            out.push(expr);
+            if let Some(expr) = trim_what {
+                out.push(expr);
+            }
+            for expr in trim_characters.iter().flatten() {
+                out.push(expr);
+            }
+        }
+        Expr::Overlay {
+            expr,
+            overlay_what,
+            overlay_from,
+            overlay_for,
+        } => {
+            out.push(expr);
+            out.push(overlay_what);
+            out.push(overlay_from);
+            if let Some(expr) = overlay_for {
+                out.push(expr);
+            }
+        }
+        Expr::MapAccess { column, keys } => {
+            out.push(column);
+            for expr in keys {
+                out.push(expr);
+            }
+        }
+        Expr::ArrayIndex { obj, indexes } => {
+            out.push(obj);
+            for expr in indexes {
+                out.push(expr);
+            }
+        }
+        Expr::Function(function) => {
+            for expr in function_args(function) {
+                out.push(expr);
+            }
+        }
+        Expr::Case {
+            operand,
+            conditions,
+            results,
+            else_result,
+        } => {
+            if let Some(expr) = operand {
+                out.push(expr);
+            }
+            for (condition, result) in conditions.iter().zip(results) {
+                out.push(condition);
+                out.push(result);
+            }
+            if let Some(expr) = else_result {
+                out.push(expr);
+            }
+        }
+        Expr::ListAgg(list_agg) => {
+            out.push(&list_agg.expr);
+            if let Some(expr) = &list_agg.separator {
+                out.push(expr);
+            }
+            if let Some(ListAggOnOverflow::Truncate {
+                filler: Some(filler),
+                ..
+            }) = &list_agg.on_overflow
+            {
+                out.push(filler);
+            }
+        }
+        Expr::ArrayAgg(array_agg) => out.push(&array_agg.expr),
+        Expr::GroupingSets(sets) | Expr::Cube(sets) | Expr::Rollup(sets) => {
+            for expr in sets.iter().flatten() {
+                out.push(expr);
+            }
+        }
+        Expr::Tuple(exprs) | Expr::Struct { values: exprs, .. } => {
+            for expr in exprs {
+                out.push(expr);
+            }
+        }
+        Expr::Array(array) => {
+            for expr in &array.elem {
+                out.push(expr);
+            }
+        }
+    }
+    out
+}
+
+/// The expressions passed as arguments to `function`, named or not.
+pub fn function_args(function: &Function) -> impl Iterator<Item = &Expr> {
+    function.args.iter().filter_map(|arg| match arg {
+        FunctionArg::Named {
+            arg: FunctionArgExpr::Expr(expr),
+            ..
+        }
+        | FunctionArg::Unnamed(FunctionArgExpr::Expr(expr)) => Some(expr),
+        _ => None,
+    })
+}
+
+/// The column references in `expr`, each as the identifiers it was written
+/// with, e.g. `["orders", "id"]` for `orders.id`.
+pub fn column_references(expr: &Expr) -> Vec<Vec<Ident>> {
+    let mut out = Vec::new();
+    visit_expr(expr, &mut |expr| match expr {
+        Expr::Identifier(id) => out.push(vec![id.clone()]),
+        Expr::CompoundIdentifier(ids) => out.push(ids.clone()),
+        _ => {}
+    });
+    out
+}
+
+/// The subqueries in `expr`, whatever they are used for.
+pub fn subqueries(expr: &Expr) -> Vec<&Query> {
+    let mut out = Vec::new();
+    visit_expr(expr, &mut |expr| match expr {
+        Expr::Subquery(query)
+        | Expr::ArraySubquery(query)
+        | Expr::Exists {
+            subquery: query, ..
+        }
+        | Expr::InSubquery {
+            subquery: query, ..
+        } => out.push(&**query),
+        _ => {}
+    });
+    out
+}
+
+/// The subqueries in `expr` whose rows only decide its value rather than
+/// make it up, as in `EXISTS (...)` and `x IN (...)`.
+pub fn filtering_subqueries(expr: &Expr) -> Vec<&Query> {
+    let mut out = Vec::new();
+    visit_expr(expr, &mut |expr| match expr {
+        Expr::Exists { subquery, .. } | Expr::InSubquery { subquery, .. } => out.push(&**subquery),
+        _ => {}
+    });
+    out
+}
+
+/// Something the value of an expression is made of.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reference<'a> {
+    /// A column, as the identifiers it was written with, e.g. `["orders",
+    /// "id"]` for `orders.id`.
+    Column(Vec<Ident>),
+    /// A scalar subquery, whose single column is the value.
+    Subquery(&'a Query),
+}
+
+/// The column references and scalar subqueries in `expr`, each along with
+/// the most transforming step between it and the value of `expr`.
+/// `function` tells how a function call transforms its argument at a
+/// position, or `None` if the argument doesn't carry lineage.
+pub fn transformed_references<'a>(
+    expr: &'a Expr,
+    function: &impl Fn(&Function, usize) -> Option<Transformation>,
+) -> Vec<(Reference<'a>, Transformation)> {
+    let mut out = Vec::new();
+    collect_transformed_references(expr, Transformation::Identity, function, &mut out);
+    out
+}
+
+fn collect_transformed_references<'a>(
+    expr: &'a Expr,
+    outer: Transformation,
+    function: &impl Fn(&Function, usize) -> Option<Transformation>,
+    out: &mut Vec<(Reference<'a>, Transformation)>,
+) {
+    let transformation = match expr {
+        Expr::Identifier(id) => return out.push((Reference::Column(vec![id.clone()]), outer)),
+        Expr::CompoundIdentifier(ids) => return out.push((Reference::Column(ids.clone()), outer)),
+        Expr::Subquery(query) => return out.push((Reference::Subquery(query), outer)),
+        // `ARRAY(SELECT ...)` collects every row of the subquery.
+        Expr::ArraySubquery(query) => {
+            return out.push((
+                Reference::Subquery(query),
+                outer.max(Transformation::Aggregation),
+            ))
+        }
+        Expr::Nested(_) | Expr::Named { .. } | Expr::AggregateExpressionWithFilter { .. } => {
+            Transformation::Identity
+        }
+        Expr::Cast { .. } | Expr::TryCast { .. } | Expr::SafeCast { .. } | Expr::Convert { .. } => {
+            Transformation::Cast
+        }
+        Expr::Case {
+            operand,
+            conditions,
+            results,
+            else_result,
+        } => {
+            let outer = outer.max(Transformation::Conditional);
+            for expr in operand.as_deref().into_iter().chain(conditions) {
+                collect_condition_references(expr, outer, function, out);
+            }
+            for expr in results.iter().chain(else_result.as_deref()) {
+                collect_transformed_references(expr, outer, function, out);
+            }
+            return;
+        }
+        Expr::Function(call) => {
+            for (position, arg) in call.args.iter().enumerate() {
+                if let FunctionArg::Named {
+                    arg: FunctionArgExpr::Expr(expr),
+                    ..
+                }
+                | FunctionArg::Unnamed(FunctionArgExpr::Expr(expr)) = arg
+                {
+                    match function(call, position) {
+                        // Like `IF(condition, then, else)`.
+                        Some(Transformation::Conditional) => collect_condition_references(
+                            expr,
+                            outer.max(Transformation::Conditional),
+                            function,
+                            out,
+                        ),
+                        Some(transformation) => collect_transformed_references(
+                            expr,
+                            outer.max(transformation),
+                            function,
+                            out,
+                        ),
+                        None => {}
+                    }
+                }
+            }
+            return;
+        }
+        Expr::ArrayAgg(_) | Expr::ListAgg(_) => Transformation::Aggregation,
+        _ => Transformation::Arithmetic,
+    };
+    for expr in sub_exprs(expr) {
+        collect_transformed_references(expr, outer.max(transformation), function, out);
+    }
+}
+
+/// Like `collect_transformed_references`, for a condition of a
+/// conditional expression, which picks the value rather than computing
+/// it: comparing columns in it, or combining comparisons, doesn't
+/// transform them any further than `outer`.
+fn collect_condition_references<'a>(
+    expr: &'a Expr,
+    outer: Transformation,
+    function: &impl Fn(&Function, usize) -> Option<Transformation>,
+    out: &mut Vec<(Reference<'a>, Transformation)>,
+) {
+    let is_predicate = match expr {
+        Expr::BinaryOp { op, .. } => matches!(
+            op,
+            BinaryOperator::Eq
+                | BinaryOperator::NotEq
+                | BinaryOperator::Lt
+                | BinaryOperator::LtEq
+                | BinaryOperator::Gt
+                | BinaryOperator::GtEq
+                | BinaryOperator::Spaceship
+                | BinaryOperator::And
+                | BinaryOperator::Or
+                | BinaryOperator::Xor
+        ),
+        Expr::UnaryOp { op, .. } => *op == UnaryOperator::Not,
+        Expr::Nested(_)
+        | Expr::IsNull(_)
+        | Expr::IsNotNull(_)
+        | Expr::IsTrue(_)
+        | Expr::IsNotTrue(_)
+        | Expr::IsFalse(_)
+        | Expr::IsNotFalse(_)
+        | Expr::IsUnknown(_)
+        | Expr::IsNotUnknown(_)
+        | Expr::IsDistinctFrom(..)
+        | Expr::IsNotDistinctFrom(..)
+        | Expr::InList { .. }
+        | Expr::InSubquery { .. }
+        | Expr::Between { .. }
+        | Expr::Like { .. }
+        | Expr::ILike { .. }
+        | Expr::SimilarTo { .. }
+        | Expr::RLike { .. } => true,
+        _ => false,
+    };
+    if !is_predicate {
+        return collect_transformed_references(expr, outer, function, out);
+    }
+    for expr in sub_exprs(expr) {
+        collect_condition_references(expr, outer, function, out);
+    }
+}
+
+/// The expressions in `expr` that decide which rows its functions see, or
+/// in which order, without contributing to its value: `FILTER` conditions,
+/// the `PARTITION BY`, `ORDER BY` and frame bounds of windows, and the
+/// `ORDER BY` and `LIMIT` of aggregates. Named windows are looked up in
+/// `windows`.
+pub fn influencing_exprs<'a>(
+    expr: &'a Expr,
+    windows: &'a [NamedWindowDefinition],
+) -> Vec<&'a Expr> {
+    let mut out = Vec::new();
+    visit_expr(expr, &mut |expr| match expr {
+        Expr::Function(function) => {
+            out.extend(function.filter.as_deref());
+            let spec = match &function.over {
+                Some(WindowType::WindowSpec(spec)) => Some(spec),
+                Some(WindowType::NamedWindow(name)) => windows
+                    .iter()
+                    .find(|NamedWindowDefinition(window, _)| window == name)
+                    .map(|NamedWindowDefinition(_, spec)| spec),
+                None => None,
+            };
+            if let Some(spec) = spec {
+                out.extend(window_exprs(spec));
+            }
+            out.extend(function.order_by.iter().map(|v| &v.expr));
+        }
+        Expr::AggregateExpressionWithFilter { filter, .. } => out.push(filter),
+        Expr::ArrayAgg(array_agg) => {
+            out.extend(array_agg.order_by.iter().flatten().map(|v| &v.expr));
+            out.extend(array_agg.limit.as_deref());
+        }
+        Expr::ListAgg(list_agg) => out.extend(list_agg.within_group.iter().map(|v| &v.expr)),
+        _ => {}
+    });
+    out
+}
+
+/// The `PARTITION BY` and `ORDER BY` expressions and frame bounds of a
+/// window.
+fn window_exprs(spec: &WindowSpec) -> impl Iterator<Item = &Expr> {
+    let bounds =
+        spec.window_frame
+            .iter()
+            .flat_map(|frame| iter::once(&frame.start_bound).chain(&frame.end_bound))
+            .filter_map(|bound| match bound {
+                WindowFrameBound::Preceding(Some(expr))
+                | WindowFrameBound::Following(Some(expr)) => Some(&**expr),
+                _ => None,
+            });
+    spec.partition_by
+        .iter()
+        .chain(spec.order_by.iter().map(|v| &v.expr))
+        .chain(bounds)
+}
@@ -0,0 +1,48 @@
+use serde::{de, Deserialize, Deserializer};
+use std::collections::HashMap;
+
+use crate::lineage::Transformation;
+
+/// The built-in function catalog, covering common ANSI, Postgres,
+/// Snowflake and BigQuery functions.
+const DEFAULT_FUNCTIONS: &str = include_str!("../functions.csv");
+
+/// How lineage flows through calls to a function.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct FunctionDefinition {
+    pub function_name: String,
+    pub transformation: Transformation,
+    /// The positions of the arguments lineage flows from, starting from 1.
+    /// Every argument carries lineage if this is empty.
+    #[serde(deserialize_with = "deserialize_arguments")]
+    pub arguments: Vec<usize>,
+}
+
+impl FunctionDefinition {
+    /// Whether the argument at `position`, starting from 0, carries
+    /// lineage.
+    pub fn carries_lineage(&self, position: usize) -> bool {
+        self.arguments.is_empty() || self.arguments.contains(&(position + 1))
+    }
+}
+
+/// Reads a space separated list of argument positions.
+fn deserialize_arguments<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<usize>, D::Error> {
+    String::deserialize(deserializer)?
+        .split_whitespace()
+        .map(|position| position.parse().map_err(de::Error::custom))
+        .collect()
+}
+
+/// The built-in function definitions, by lowercase function name.
+pub fn default_functions() -> HashMap<String, FunctionDefinition> {
+    let mut functions = HashMap::new();
+    for definition in csv::Reader::from_reader(DEFAULT_FUNCTIONS.as_bytes()).deserialize() {
+        let definition: FunctionDefinition =
+            definition.expect("Failed to parse the built-in function catalog");
+        functions.insert(definition.function_name.to_lowercase(), definition);
+    }
+    functions
+}
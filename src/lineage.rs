@@ -0,0 +1,185 @@
+use serde::Deserialize;
+use sqlparser::ast::Statement;
+use sqlparser::tokenizer::Location;
+use std::fmt;
+
+/// Where a statement starts and ends in the input, as 1-based line and
+/// column numbers. The end is exclusive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SourceSpan {
+    pub start: Location,
+    pub end: Location,
+}
+
+/// A parsed statement along with where it was found in the input.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceStatement {
+    /// Position of the statement in the input, starting from 0.
+    pub index: usize,
+    pub statement: Statement,
+    pub span: SourceSpan,
+    pub sql: String,
+}
+
+/// How an output column is derived from one of its upstream columns, from
+/// the least to the most transforming.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transformation {
+    /// The upstream column is passed through as is.
+    Identity,
+    /// The upstream column is passed through under another name.
+    Rename,
+    /// The upstream column is cast to another type.
+    Cast,
+    /// The upstream column is one of the branches or conditions of a
+    /// conditional expression like `CASE` or `COALESCE`.
+    Conditional,
+    /// The upstream column goes through arithmetic or a scalar function.
+    Arithmetic,
+    /// The upstream column is computed over a window of rows.
+    Window,
+    /// The upstream column is aggregated over a group of rows.
+    Aggregation,
+    /// The upstream column is hashed or masked.
+    Masking,
+}
+
+/// An output column of a statement and the upstream columns it reads from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnLineage {
+    pub name: String,
+    /// Every upstream column along with how it is transformed into this
+    /// column.
+    pub upstream: Vec<(String, Transformation)>,
+    /// Whether some of the upstream columns are only candidates of an
+    /// ambiguous column reference.
+    pub ambiguous: bool,
+    /// Upstream columns that don't make up the value of the column but
+    /// decide which rows it has, e.g. the columns of a `WHERE`, `JOIN ...
+    /// ON` or `GROUP BY` clause.
+    pub indirect: Vec<String>,
+}
+
+/// The lineage of every output column of a single statement.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatementLineage {
+    /// Position of the statement in the input, starting from 0.
+    pub index: usize,
+    pub span: SourceSpan,
+    /// The statement as it was written in the input.
+    pub sql: String,
+    /// The table the statement writes its columns to, if it isn't a plain
+    /// query.
+    pub target: Option<String>,
+    pub columns: Vec<ColumnLineage>,
+    /// The lineage of the columns each clause of a statement with more than
+    /// one way of writing to its target writes, like the `WHEN` clauses of
+    /// a `MERGE`, in the order of the clauses.
+    pub clauses: Vec<ClauseLineage>,
+}
+
+/// How a `WHEN` clause of a `MERGE` writes to its target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClauseKind {
+    /// `WHEN MATCHED THEN UPDATE`, which updates the matched target row.
+    MatchedUpdate,
+    /// `WHEN NOT MATCHED THEN INSERT`, which inserts a row for a source row
+    /// without a match.
+    NotMatchedInsert,
+}
+
+/// The lineage of the columns a single clause of a statement writes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClauseLineage {
+    pub kind: ClauseKind,
+    /// The condition the clause adds, as in `WHEN MATCHED AND condition`,
+    /// as written.
+    pub predicate: Option<String>,
+    pub columns: Vec<ColumnLineage>,
+}
+
+impl fmt::Display for SourceSpan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}-{}:{}",
+            self.start.line, self.start.column, self.end.line, self.end.column
+        )
+    }
+}
+
+impl fmt::Display for Transformation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Transformation::Identity => "identity",
+            Transformation::Rename => "rename",
+            Transformation::Cast => "cast",
+            Transformation::Conditional => "conditional",
+            Transformation::Arithmetic => "arithmetic",
+            Transformation::Window => "window",
+            Transformation::Aggregation => "aggregation",
+            Transformation::Masking => "masking",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl fmt::Display for ColumnLineage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.name)?;
+        for (position, (upstream, transformation)) in self.upstream.iter().enumerate() {
+            if position > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{upstream}")?;
+            if *transformation != Transformation::Identity {
+                write!(f, " ({transformation})")?;
+            }
+        }
+        if self.ambiguous {
+            write!(f, " (ambiguous)")?;
+        }
+        if !self.indirect.is_empty() {
+            write!(f, "; indirect: {}", self.indirect.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ClauseLineage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (when, then) = match self.kind {
+            ClauseKind::MatchedUpdate => ("WHEN MATCHED", "THEN UPDATE"),
+            ClauseKind::NotMatchedInsert => ("WHEN NOT MATCHED", "THEN INSERT"),
+        };
+        write!(f, "{when}")?;
+        if let Some(predicate) = &self.predicate {
+            write!(f, " AND {predicate}")?;
+        }
+        write!(f, " {then}")
+    }
+}
+
+impl fmt::Display for StatementLineage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "-- statement {} ({})", self.index, self.span)?;
+        if let Some(target) = &self.target {
+            write!(f, " into {target}")?;
+        }
+        writeln!(f)?;
+        writeln!(f, "{}", self.sql)?;
+        if self.clauses.is_empty() {
+            for column in &self.columns {
+                writeln!(f, "--   {column}")?;
+            }
+        }
+        for clause in &self.clauses {
+            writeln!(f, "--   {clause}:")?;
+            for column in &clause.columns {
+                writeln!(f, "--     {column}")?;
+            }
+        }
+        Ok(())
+    }
+}
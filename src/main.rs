@@ -2,9 +2,21 @@ use clap::Parser;
 use std::path::PathBuf;
 
 mod analytics;
+mod dialect;
+mod error;
+mod expr;
+mod functions;
+mod lineage;
 #[cfg(test)]
 mod test;
 pub use analytics::{FullColumn, QueryAnalytics};
+pub use dialect::Dialect;
+pub use error::LineageError;
+pub use functions::FunctionDefinition;
+pub use lineage::{
+    ClauseKind, ClauseLineage, ColumnLineage, SourceSpan, SourceStatement, StatementLineage,
+    Transformation,
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -13,6 +25,26 @@ struct Opts {
     csv: PathBuf,
     #[arg(short, long, default_value = "queries.sql")]
     sql: PathBuf,
+    /// The dialect the queries are written in. This decides how they are
+    /// parsed, how identifiers are matched against the catalog and how
+    /// columns without an alias are named.
+    #[arg(short, long, value_enum, default_value_t = Dialect::Generic)]
+    dialect: Dialect,
+    /// Record every candidate of an ambiguous column reference instead of
+    /// failing the statement.
+    #[arg(short, long)]
+    permissive: bool,
+    /// A csv of function definitions to use on top of the built-in ones,
+    /// see `functions.csv`.
+    #[arg(short, long)]
+    functions: Option<PathBuf>,
+    /// The database table names without one are looked up in.
+    #[arg(long)]
+    database: Option<String>,
+    /// The schemas table names without one are looked up in, in order, as
+    /// a comma separated list.
+    #[arg(long, value_delimiter = ',')]
+    search_path: Vec<String>,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -22,10 +54,24 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let upstream_columns: Vec<FullColumn> = rdr
         .deserialize::<FullColumn>()
         .collect::<Result<Vec<FullColumn>, csv::Error>>()?;
+    let functions = match opts.functions {
+        Some(path) => csv::Reader::from_path(path)?
+            .deserialize::<FunctionDefinition>()
+            .collect::<Result<Vec<FunctionDefinition>, csv::Error>>()?,
+        None => Vec::new(),
+    };
     let sql = std::fs::read_to_string(opts.sql)?;
-    let mut analytics = QueryAnalytics::from(upstream_columns);
-    analytics.get_query_lineage(sql)?;
-    let lineage = analytics.dependency_map;
-    println!("{lineage:#?}");
+    let mut analytics = QueryAnalytics::from(upstream_columns)
+        .with_dialect(opts.dialect)
+        .with_permissive(opts.permissive)
+        .with_functions(functions)
+        .with_database(opts.database)
+        .with_search_path(opts.search_path);
+    for statement in analytics.collect_query_lineage(&sql) {
+        match statement {
+            Ok(statement) => println!("{statement}"),
+            Err(error) => eprintln!("{error}\n"),
+        }
+    }
     Ok(())
 }
@@ -19,11 +19,17 @@ group by order_items.order_id;"#;
     let expected = HashMap::from([
         (
             "item_count".to_string(),
-            (vec!["prod.integrations.order_items.id".to_string()], true),
+            vec![(
+                "prod.integrations.order_items.id".to_string(),
+                Transformation::Aggregation,
+            )],
         ),
         (
             "id".to_string(),
-            (vec!["prod.integrations.orders.id".to_string()], false),
+            vec![(
+                "prod.integrations.orders.id".to_string(),
+                Transformation::Identity,
+            )],
         ),
     ]);
     assert_eq!(analytics.dependency_map, expected);
@@ -43,7 +49,10 @@ select id from orders;"#;
         .expect("Failed to get lineage");
     let expected = HashMap::from([(
         "id".to_string(),
-        (vec!["prod.integrations.orders.id".to_string()], false),
+        vec![(
+            "prod.integrations.orders.id".to_string(),
+            Transformation::Identity,
+        )],
     )]);
     assert_eq!(analytics.dependency_map, expected);
 }
@@ -62,7 +71,10 @@ select id as foobar from orders;"#;
         .expect("Failed to get lineage");
     let expected = HashMap::from([(
         "foobar".to_string(),
-        (vec!["prod.integrations.orders.id".to_string()], false),
+        vec![(
+            "prod.integrations.orders.id".to_string(),
+            Transformation::Rename,
+        )],
     )]);
     assert_eq!(analytics.dependency_map, expected);
 }
@@ -81,7 +93,10 @@ select count(id) as foobar from orders;"#;
         .expect("Failed to get lineage");
     let expected = HashMap::from([(
         "foobar".to_string(),
-        (vec!["prod.integrations.orders.id".to_string()], true),
+        vec![(
+            "prod.integrations.orders.id".to_string(),
+            Transformation::Aggregation,
+        )],
     )]);
     assert_eq!(analytics.dependency_map, expected);
 }
@@ -103,13 +118,16 @@ select order_id from platform.order_items;"#;
         .expect("Failed to get lineage");
     let expected = HashMap::from([(
         "order_id".to_string(),
-        (
-            vec![
+        vec![
+            (
                 "prod.integrations.order_items.order_id".to_string(),
+                Transformation::Identity,
+            ),
+            (
                 "prod.platform.order_items.order_id".to_string(),
-            ],
-            false,
-        ),
+                Transformation::Identity,
+            ),
+        ],
     )]);
     assert_eq!(analytics.dependency_map, expected);
 }
@@ -130,13 +148,16 @@ where order_items.order_id = orders.id;"#;
         .expect("Failed to get lineage");
     let expected = HashMap::from([(
         "total_price".to_string(),
-        (
-            vec![
+        vec![
+            (
                 "prod.platform.order_items.count".to_string(),
+                Transformation::Aggregation,
+            ),
+            (
                 "prod.integrations.orders.price".to_string(),
-            ],
-            true,
-        ),
+                Transformation::Aggregation,
+            ),
+        ],
     )]);
     assert_eq!(analytics.dependency_map, expected);
 }
@@ -161,15 +182,2285 @@ where order_items.my_order_id = orders.id;"#;
         .expect("Failed to get lineage");
     let expected = HashMap::from([(
         "total_price".to_string(),
+        vec![
+            (
+                "prod.integrations.order_items.count".to_string(),
+                Transformation::Aggregation,
+            ),
+            (
+                "prod.platform.order_items.count".to_string(),
+                Transformation::Aggregation,
+            ),
+            (
+                "prod.integrations.orders.price".to_string(),
+                Transformation::Aggregation,
+            ),
+        ],
+    )]);
+    assert_eq!(analytics.dependency_map, expected);
+}
+#[test]
+fn per_statement_lineage() {
+    let sql = r#"
+select id from orders;
+select id, count(order_id) as item_count
+from integrations.order_items;"#;
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+    let mut analytics = QueryAnalytics::from(columns);
+    let lineage = analytics
+        .get_query_lineage(sql.to_string())
+        .expect("Failed to get lineage");
+    assert_eq!(lineage.len(), 2);
+
+    assert_eq!(lineage[0].index, 0);
+    assert_eq!(lineage[0].sql, "select id from orders");
+    assert_eq!(lineage[0].span.start.line, 2);
+    assert_eq!(lineage[0].span.start.column, 1);
+    assert_eq!(
+        lineage[0].columns,
+        vec![ColumnLineage {
+            name: "id".to_string(),
+            upstream: vec![(
+                "prod.integrations.orders.id".to_string(),
+                Transformation::Identity
+            )],
+            ambiguous: false,
+            indirect: vec![],
+        }]
+    );
+
+    assert_eq!(lineage[1].index, 1);
+    assert_eq!(
+        lineage[1].sql,
+        "select id, count(order_id) as item_count\nfrom integrations.order_items"
+    );
+    assert_eq!(lineage[1].span.start.line, 3);
+    assert_eq!(lineage[1].span.end.line, 4);
+    assert_eq!(
+        lineage[1].columns[0],
+        ColumnLineage {
+            name: "id".to_string(),
+            upstream: vec![(
+                "prod.integrations.order_items.id".to_string(),
+                Transformation::Identity
+            )],
+            ambiguous: false,
+            indirect: vec![],
+        }
+    );
+
+    // The merged map still combines both statements.
+    assert_eq!(
+        analytics.dependency_map["id"],
+        vec![
+            (
+                "prod.integrations.orders.id".to_string(),
+                Transformation::Identity
+            ),
+            (
+                "prod.integrations.order_items.id".to_string(),
+                Transformation::Identity
+            ),
+        ]
+    );
+
+    // Statements sharing a line, or coming after one that doesn't parse,
+    // still get their own text.
+    let sql = "select id from orders; select id id id; select price\nfrom orders;\n".repeat(200);
+    let lineage = analytics.collect_query_lineage(&sql);
+    assert_eq!(lineage.len(), 600);
+    let last = lineage[599].as_ref().expect("Failed to get lineage");
+    assert_eq!(last.sql, "select price\nfrom orders");
+    assert_eq!(
+        last.span,
+        SourceSpan {
+            start: sqlparser::tokenizer::Location {
+                line: 399,
+                column: 41
+            },
+            end: sqlparser::tokenizer::Location {
+                line: 400,
+                column: 12
+            },
+        }
+    );
+}
+#[test]
+fn wildcard() {
+    let sql = r#"
+select * from orders;"#;
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+    let mut analytics = QueryAnalytics::from(columns);
+    analytics
+        .get_query_lineage(sql.to_string())
+        .expect("Failed to get lineage");
+    let expected = HashMap::from([
         (
-            vec![
+            "id".to_string(),
+            vec![(
+                "prod.integrations.orders.id".to_string(),
+                Transformation::Identity,
+            )],
+        ),
+        (
+            "item_name".to_string(),
+            vec![(
+                "prod.integrations.orders.item_name".to_string(),
+                Transformation::Identity,
+            )],
+        ),
+        (
+            "customer_name".to_string(),
+            vec![(
+                "prod.integrations.orders.customer_name".to_string(),
+                Transformation::Identity,
+            )],
+        ),
+        (
+            "price".to_string(),
+            vec![(
+                "prod.integrations.orders.price".to_string(),
+                Transformation::Identity,
+            )],
+        ),
+    ]);
+    assert_eq!(analytics.dependency_map, expected);
+}
+#[test]
+fn qualified_wildcard_with_options() {
+    let sql = r#"
+select order_items.* except (id, order_id) rename (date as order_date)
+from orders, integrations.order_items;"#;
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+    let mut analytics = QueryAnalytics::from(columns);
+    analytics
+        .get_query_lineage(sql.to_string())
+        .expect("Failed to get lineage");
+    let expected = HashMap::from([
+        (
+            "order_date".to_string(),
+            vec![(
+                "prod.integrations.order_items.date".to_string(),
+                Transformation::Rename,
+            )],
+        ),
+        (
+            "count".to_string(),
+            vec![(
+                "prod.integrations.order_items.count".to_string(),
+                Transformation::Identity,
+            )],
+        ),
+    ]);
+    assert_eq!(analytics.dependency_map, expected);
+}
+#[test]
+fn wildcard_over_derived_table() {
+    let sql = r#"
+select * replace (price * count as price)
+from (select id, price from orders) priced, platform.order_items;"#;
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+    let mut analytics = QueryAnalytics::from(columns);
+    let lineage = analytics
+        .get_query_lineage(sql.to_string())
+        .expect("Failed to get lineage");
+    let names = lineage[0]
+        .columns
+        .iter()
+        .map(|column| column.name.as_str())
+        .collect::<Vec<&str>>();
+    assert_eq!(
+        names,
+        vec!["id", "price", "order_id", "date", "item_name", "count"]
+    );
+    assert_eq!(
+        analytics.dependency_map["price"],
+        vec![
+            (
+                "prod.integrations.orders.price".to_string(),
+                Transformation::Arithmetic
+            ),
+            (
+                "prod.platform.order_items.count".to_string(),
+                Transformation::Arithmetic
+            ),
+        ]
+    );
+}
+#[test]
+fn wildcard_columns() {
+    let sql = r#"
+select * from orders, integrations.order_items;
+select * from integrations.order_items i join platform.order_items p using (order_id);
+select x.* from orders;"#;
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+    let mut analytics = QueryAnalytics::from(columns);
+    let lineage = analytics.collect_query_lineage(sql);
+    let names = |index: usize| {
+        lineage[index]
+            .as_ref()
+            .expect("Failed to get lineage")
+            .columns
+            .iter()
+            .map(|column| column.name.clone())
+            .collect::<Vec<String>>()
+    };
+    // Columns of the same name from different tables stay apart.
+    assert_eq!(
+        names(0),
+        vec![
+            "id",
+            "item_name",
+            "customer_name",
+            "price",
+            "id",
+            "order_id",
+            "date",
+            "count"
+        ]
+    );
+    // Only the column the join is USING is coalesced.
+    assert_eq!(
+        names(1),
+        vec![
+            "id",
+            "order_id",
+            "date",
+            "count",
+            "date",
+            "item_name",
+            "count"
+        ]
+    );
+    assert_eq!(
+        lineage[1].as_ref().expect("Failed to get lineage").columns[1].upstream,
+        vec![
+            (
+                "prod.integrations.order_items.order_id".to_string(),
+                Transformation::Identity
+            ),
+            (
+                "prod.platform.order_items.order_id".to_string(),
+                Transformation::Identity
+            ),
+        ]
+    );
+    assert_eq!(
+        lineage[2],
+        Err(LineageError::UnknownTable {
+            statement: 2,
+            location: sqlparser::tokenizer::Location { line: 4, column: 1 },
+            table: "x".to_string(),
+        })
+    );
+}
+#[test]
+fn common_table_expressions() {
+    let sql = r#"
+with item_totals as (
+  select order_id, sum(count) as total from integrations.order_items
+), big_orders (big_order_id, big_total) as (
+  select order_id, total from item_totals
+)
+select big_order_id, big_total, price
+from big_orders, orders;"#;
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+    let mut analytics = QueryAnalytics::from(columns);
+    analytics
+        .get_query_lineage(sql.to_string())
+        .expect("Failed to get lineage");
+    let expected = HashMap::from([
+        (
+            "big_order_id".to_string(),
+            vec![(
+                "prod.integrations.order_items.order_id".to_string(),
+                Transformation::Rename,
+            )],
+        ),
+        (
+            "big_total".to_string(),
+            vec![(
                 "prod.integrations.order_items.count".to_string(),
-                // TODO: Fix this.
-                //"prod.platform.order_items.count".to_string(),
-                //"prod.integrations.orders.price".to_string(),
+                Transformation::Aggregation,
+            )],
+        ),
+        (
+            "price".to_string(),
+            vec![(
+                "prod.integrations.orders.price".to_string(),
+                Transformation::Identity,
+            )],
+        ),
+    ]);
+    assert_eq!(analytics.dependency_map, expected);
+}
+#[test]
+fn common_table_expression_wildcard() {
+    let sql = r#"
+with ids as (select id, price as cost from orders)
+select ids.* from ids;"#;
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+    let mut analytics = QueryAnalytics::from(columns);
+    analytics
+        .get_query_lineage(sql.to_string())
+        .expect("Failed to get lineage");
+    let expected = HashMap::from([
+        (
+            "id".to_string(),
+            vec![(
+                "prod.integrations.orders.id".to_string(),
+                Transformation::Identity,
+            )],
+        ),
+        (
+            "cost".to_string(),
+            vec![(
+                "prod.integrations.orders.price".to_string(),
+                Transformation::Rename,
+            )],
+        ),
+    ]);
+    assert_eq!(analytics.dependency_map, expected);
+}
+#[test]
+fn recursive_common_table_expression() {
+    let sql = r#"
+with recursive chain (a, b) as (
+  select order_id, item_name from platform.order_items
+  union all
+  select chain.b, id from chain, integrations.order_items
+)
+select a, b from chain;"#;
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+    let mut analytics = QueryAnalytics::from(columns);
+    analytics
+        .get_query_lineage(sql.to_string())
+        .expect("Failed to get lineage");
+    let expected = HashMap::from([
+        (
+            "a".to_string(),
+            vec![
+                (
+                    "prod.platform.order_items.order_id".to_string(),
+                    Transformation::Rename,
+                ),
+                (
+                    "prod.platform.order_items.item_name".to_string(),
+                    Transformation::Rename,
+                ),
+                (
+                    "prod.integrations.order_items.id".to_string(),
+                    Transformation::Rename,
+                ),
+            ],
+        ),
+        (
+            "b".to_string(),
+            vec![
+                (
+                    "prod.platform.order_items.item_name".to_string(),
+                    Transformation::Rename,
+                ),
+                (
+                    "prod.integrations.order_items.id".to_string(),
+                    Transformation::Rename,
+                ),
+            ],
+        ),
+    ]);
+    assert_eq!(analytics.dependency_map, expected);
+}
+#[test]
+fn expressions() {
+    let sql = r#"
+select
+  case when price > 0 then item_name else customer_name end as label,
+  cast(price as int) as int_price,
+  -price as negative_price,
+  coalesce(upper(item_name), 'none') as item,
+  id in (1, 2, 3) as is_first,
+  price between 1 and orders.id as in_range,
+  customer_name like 'a%' as is_a,
+  (price) as parenthesized,
+  'literal' as constant,
+  case when price * 2 > 10 then 'big' end as size,
+  iff(price > 0 and id is not null, item_name, null) as named
+from orders;"#;
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+    let mut analytics = QueryAnalytics::from(columns);
+    let lineage = analytics
+        .get_query_lineage(sql.to_string())
+        .expect("Failed to get lineage");
+    let expected = HashMap::from([
+        (
+            "label".to_string(),
+            vec![
+                // Comparing the column only picks a branch.
+                (
+                    "prod.integrations.orders.price".to_string(),
+                    Transformation::Conditional,
+                ),
+                (
+                    "prod.integrations.orders.item_name".to_string(),
+                    Transformation::Conditional,
+                ),
+                (
+                    "prod.integrations.orders.customer_name".to_string(),
+                    Transformation::Conditional,
+                ),
+            ],
+        ),
+        (
+            "int_price".to_string(),
+            vec![(
+                "prod.integrations.orders.price".to_string(),
+                Transformation::Cast,
+            )],
+        ),
+        (
+            "negative_price".to_string(),
+            vec![(
+                "prod.integrations.orders.price".to_string(),
+                Transformation::Arithmetic,
+            )],
+        ),
+        (
+            "item".to_string(),
+            vec![(
+                "prod.integrations.orders.item_name".to_string(),
+                Transformation::Arithmetic,
+            )],
+        ),
+        (
+            "is_first".to_string(),
+            vec![(
+                "prod.integrations.orders.id".to_string(),
+                Transformation::Arithmetic,
+            )],
+        ),
+        (
+            "in_range".to_string(),
+            vec![
+                (
+                    "prod.integrations.orders.price".to_string(),
+                    Transformation::Arithmetic,
+                ),
+                (
+                    "prod.integrations.orders.id".to_string(),
+                    Transformation::Arithmetic,
+                ),
+            ],
+        ),
+        (
+            "is_a".to_string(),
+            vec![(
+                "prod.integrations.orders.customer_name".to_string(),
+                Transformation::Arithmetic,
+            )],
+        ),
+        (
+            "parenthesized".to_string(),
+            vec![(
+                "prod.integrations.orders.price".to_string(),
+                Transformation::Rename,
+            )],
+        ),
+        (
+            "size".to_string(),
+            vec![(
+                "prod.integrations.orders.price".to_string(),
+                Transformation::Arithmetic,
+            )],
+        ),
+        (
+            "named".to_string(),
+            vec![
+                (
+                    "prod.integrations.orders.price".to_string(),
+                    Transformation::Conditional,
+                ),
+                (
+                    "prod.integrations.orders.id".to_string(),
+                    Transformation::Conditional,
+                ),
+                (
+                    "prod.integrations.orders.item_name".to_string(),
+                    Transformation::Conditional,
+                ),
             ],
-            true,
         ),
+    ]);
+    assert_eq!(analytics.dependency_map, expected);
+    // Literals are still output columns, they just have no upstream.
+    assert!(lineage[0]
+        .columns
+        .iter()
+        .any(|column| column.name == "constant" && column.upstream.is_empty()));
+}
+#[test]
+fn nested_aggregate_is_aggregation() {
+    let sql = r#"
+select round(sum(price) / count(id), 2) as average_price from orders;"#;
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+    let mut analytics = QueryAnalytics::from(columns);
+    analytics
+        .get_query_lineage(sql.to_string())
+        .expect("Failed to get lineage");
+    let expected = HashMap::from([(
+        "average_price".to_string(),
+        vec![
+            (
+                "prod.integrations.orders.price".to_string(),
+                Transformation::Aggregation,
+            ),
+            (
+                "prod.integrations.orders.id".to_string(),
+                Transformation::Aggregation,
+            ),
+        ],
     )]);
     assert_eq!(analytics.dependency_map, expected);
 }
+#[test]
+fn unnamed_expressions() {
+    let sql = r#"
+select price * 2, count(id), cast(price as int), id from orders;"#;
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+    let names = |dialect: Dialect, columns: Vec<FullColumn>| {
+        let mut analytics = QueryAnalytics::from(columns).with_dialect(dialect);
+        let lineage = analytics
+            .get_query_lineage(sql.to_string())
+            .expect("Failed to get lineage");
+        lineage[0]
+            .columns
+            .iter()
+            .map(|column| column.name.clone())
+            .collect::<Vec<String>>()
+    };
+    assert_eq!(
+        names(Dialect::Generic, columns.clone()),
+        vec!["price * 2", "count(id)", "CAST(price AS INT)", "id"]
+    );
+    assert_eq!(
+        names(Dialect::Postgres, columns.clone()),
+        vec!["?column?", "count", "price", "id"]
+    );
+    // Snowflake stores unquoted names in upper case.
+    let upper_columns = columns
+        .iter()
+        .map(|column| FullColumn {
+            database_name: column.database_name.to_uppercase(),
+            schema_name: column.schema_name.to_uppercase(),
+            table_name: column.table_name.to_uppercase(),
+            column_name: column.column_name.to_uppercase(),
+        })
+        .collect::<Vec<FullColumn>>();
+    assert_eq!(
+        names(Dialect::Snowflake, upper_columns),
+        vec!["PRICE * 2", "COUNT(ID)", "CAST(PRICE AS INT)", "ID"]
+    );
+    assert_eq!(
+        names(Dialect::Spark, columns.clone()),
+        vec!["_c0", "_c1", "_c2", "id"]
+    );
+    assert_eq!(
+        names(Dialect::BigQuery, columns.clone()),
+        vec!["f0_", "f1_", "f2_", "id"]
+    );
+
+    let mut analytics = QueryAnalytics::from(columns).with_dialect(Dialect::Spark);
+    analytics
+        .get_query_lineage(sql.to_string())
+        .expect("Failed to get lineage");
+    assert_eq!(
+        analytics.dependency_map["_c1"],
+        vec![(
+            "prod.integrations.orders.id".to_string(),
+            Transformation::Aggregation
+        )]
+    );
+}
+#[test]
+fn errors_per_statement() {
+    let sql = r#"
+select id from orders;
+select (id from orders;
+select id from missing;
+values (1, 2);
+select price from orders;"#;
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+    let mut analytics = QueryAnalytics::from(columns);
+    let lineage = analytics.collect_query_lineage(sql);
+    assert_eq!(lineage.len(), 5);
+    assert!(lineage[0].is_ok());
+    // The error points at the token the parser failed at.
+    assert!(matches!(
+        lineage[1],
+        Err(LineageError::Parse {
+            statement: 1,
+            location: sqlparser::tokenizer::Location {
+                line: 3,
+                column: 12
+            },
+            ..
+        })
+    ));
+    assert_eq!(
+        lineage[2],
+        Err(LineageError::UnknownTable {
+            statement: 2,
+            location: sqlparser::tokenizer::Location { line: 4, column: 1 },
+            table: "missing".to_string(),
+        })
+    );
+    assert!(matches!(
+        &lineage[3],
+        Err(LineageError::Unsupported { statement: 3, construct, .. }) if construct == "VALUES"
+    ));
+    let last = lineage[4].as_ref().expect("Failed to get lineage");
+    assert_eq!(last.index, 4);
+    assert_eq!(
+        last.columns[0].upstream,
+        vec![(
+            "prod.integrations.orders.price".to_string(),
+            Transformation::Identity
+        )]
+    );
+
+    let error = analytics
+        .get_query_lineage(sql.to_string())
+        .expect_err("Expected the parse error");
+    assert_eq!(error.statement(), 1);
+
+    let lineage = analytics.collect_query_lineage("select id from orders; garbage here;");
+    assert_eq!(
+        lineage[1].as_ref().map_err(LineageError::location),
+        Err(sqlparser::tokenizer::Location {
+            line: 1,
+            column: 24
+        })
+    );
+    // A table that doesn't exist is an error even if no column is read
+    // from it.
+    let lineage =
+        analytics.collect_query_lineage("select count(*) from nope; select 1 as one from nope;");
+    assert_eq!(lineage.len(), 2);
+    for (index, lineage) in lineage.iter().enumerate() {
+        assert!(matches!(
+            lineage,
+            Err(LineageError::UnknownTable { statement, table, .. })
+                if *statement == index && table == "nope"
+        ));
+    }
+
+    let lineage = analytics.collect_query_lineage("select id from orders o p;");
+    assert_eq!(
+        lineage[0],
+        Err(LineageError::Parse {
+            statement: 0,
+            location: sqlparser::tokenizer::Location {
+                line: 1,
+                column: 25
+            },
+            message: "Expected end of statement, found: p".to_string(),
+        })
+    );
+}
+#[test]
+fn ambiguous_column() {
+    let sql = r#"
+select order_id from integrations.order_items, platform.order_items;"#;
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+    let mut analytics = QueryAnalytics::from(columns.clone());
+    let error = analytics
+        .get_query_lineage(sql.to_string())
+        .expect_err("Expected the column to be ambiguous");
+    assert_eq!(
+        error,
+        LineageError::AmbiguousColumn {
+            statement: 0,
+            location: sqlparser::tokenizer::Location { line: 2, column: 1 },
+            column: "order_id".to_string(),
+            candidates: vec![
+                "prod.integrations.order_items.order_id".to_string(),
+                "prod.platform.order_items.order_id".to_string(),
+            ],
+        }
+    );
+
+    let mut analytics = QueryAnalytics::from(columns).with_permissive(true);
+    let lineage = analytics
+        .get_query_lineage(sql.to_string())
+        .expect("Failed to get lineage");
+    assert_eq!(
+        lineage[0].columns,
+        vec![ColumnLineage {
+            name: "order_id".to_string(),
+            upstream: vec![
+                (
+                    "prod.integrations.order_items.order_id".to_string(),
+                    Transformation::Identity
+                ),
+                (
+                    "prod.platform.order_items.order_id".to_string(),
+                    Transformation::Identity
+                ),
+            ],
+            ambiguous: true,
+            indirect: vec![],
+        }]
+    );
+}
+#[test]
+fn table_aliases() {
+    let sql = r#"
+select o.id as order_id, oi.id as item_id from orders o, integrations.order_items oi;
+select a.id, b.price from orders a, orders b;
+select orders.id from orders o;
+select id from orders a, orders b;"#;
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+    let mut analytics = QueryAnalytics::from(columns);
+    let lineage = analytics.collect_query_lineage(sql);
+    assert_eq!(
+        lineage[0].as_ref().expect("Failed to get lineage").columns,
+        vec![
+            ColumnLineage {
+                name: "order_id".to_string(),
+                upstream: vec![(
+                    "prod.integrations.orders.id".to_string(),
+                    Transformation::Rename
+                )],
+                ambiguous: false,
+                indirect: vec![],
+            },
+            ColumnLineage {
+                name: "item_id".to_string(),
+                upstream: vec![(
+                    "prod.integrations.order_items.id".to_string(),
+                    Transformation::Rename
+                )],
+                ambiguous: false,
+                indirect: vec![],
+            },
+        ]
+    );
+    assert_eq!(
+        lineage[1].as_ref().expect("Failed to get lineage").columns,
+        vec![
+            ColumnLineage {
+                name: "id".to_string(),
+                upstream: vec![(
+                    "prod.integrations.orders.id".to_string(),
+                    Transformation::Identity
+                )],
+                ambiguous: false,
+                indirect: vec![],
+            },
+            ColumnLineage {
+                name: "price".to_string(),
+                upstream: vec![(
+                    "prod.integrations.orders.price".to_string(),
+                    Transformation::Identity
+                )],
+                ambiguous: false,
+                indirect: vec![],
+            },
+        ]
+    );
+    // Once a table is aliased, it can only be referred to by its alias.
+    assert_eq!(
+        lineage[2],
+        Err(LineageError::UnknownTable {
+            statement: 2,
+            location: sqlparser::tokenizer::Location { line: 4, column: 1 },
+            table: "orders".to_string(),
+        })
+    );
+    assert!(matches!(
+        lineage[3],
+        Err(LineageError::AmbiguousColumn { ref column, .. }) if column == "id"
+    ));
+}
+#[test]
+fn joins() {
+    let sql = r#"
+select o.customer_name, oi.date
+from orders o
+join integrations.order_items oi on oi.order_id = o.id
+left join platform.order_items p on p.order_id = o.id;
+select order_id, date
+from integrations.order_items i
+full join platform.order_items p using (order_id, date);
+select count from integrations.order_items join platform.order_items using (order_id);
+select order_id from integrations.order_items natural join platform.order_items;
+select p.item_name from (orders cross join platform.order_items p);"#;
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+    let mut analytics = QueryAnalytics::from(columns);
+    let lineage = analytics.collect_query_lineage(sql);
+    assert_eq!(
+        lineage[0].as_ref().expect("Failed to get lineage").columns,
+        vec![
+            ColumnLineage {
+                name: "customer_name".to_string(),
+                upstream: vec![(
+                    "prod.integrations.orders.customer_name".to_string(),
+                    Transformation::Identity
+                )],
+                ambiguous: false,
+                indirect: vec![
+                    "prod.integrations.order_items.order_id".to_string(),
+                    "prod.integrations.orders.id".to_string(),
+                    "prod.platform.order_items.order_id".to_string(),
+                ],
+            },
+            ColumnLineage {
+                name: "date".to_string(),
+                upstream: vec![(
+                    "prod.integrations.order_items.date".to_string(),
+                    Transformation::Identity
+                )],
+                ambiguous: false,
+                indirect: vec![
+                    "prod.integrations.order_items.order_id".to_string(),
+                    "prod.integrations.orders.id".to_string(),
+                    "prod.platform.order_items.order_id".to_string(),
+                ],
+            },
+        ]
+    );
+    // USING columns are coalesced from both sides, other shared columns
+    // are still ambiguous.
+    assert_eq!(
+        lineage[1].as_ref().expect("Failed to get lineage").columns,
+        vec![
+            ColumnLineage {
+                name: "order_id".to_string(),
+                upstream: vec![
+                    (
+                        "prod.integrations.order_items.order_id".to_string(),
+                        Transformation::Identity
+                    ),
+                    (
+                        "prod.platform.order_items.order_id".to_string(),
+                        Transformation::Identity
+                    ),
+                ],
+                ambiguous: false,
+                indirect: vec![
+                    "prod.integrations.order_items.order_id".to_string(),
+                    "prod.platform.order_items.order_id".to_string(),
+                    "prod.integrations.order_items.date".to_string(),
+                    "prod.platform.order_items.date".to_string(),
+                ],
+            },
+            ColumnLineage {
+                name: "date".to_string(),
+                upstream: vec![
+                    (
+                        "prod.integrations.order_items.date".to_string(),
+                        Transformation::Identity
+                    ),
+                    (
+                        "prod.platform.order_items.date".to_string(),
+                        Transformation::Identity
+                    ),
+                ],
+                ambiguous: false,
+                indirect: vec![
+                    "prod.integrations.order_items.order_id".to_string(),
+                    "prod.platform.order_items.order_id".to_string(),
+                    "prod.integrations.order_items.date".to_string(),
+                    "prod.platform.order_items.date".to_string(),
+                ],
+            },
+        ]
+    );
+    assert!(matches!(
+        lineage[2],
+        Err(LineageError::AmbiguousColumn { ref column, .. }) if column == "count"
+    ));
+    assert_eq!(
+        lineage[3].as_ref().expect("Failed to get lineage").columns,
+        vec![ColumnLineage {
+            name: "order_id".to_string(),
+            upstream: vec![
+                (
+                    "prod.integrations.order_items.order_id".to_string(),
+                    Transformation::Identity
+                ),
+                (
+                    "prod.platform.order_items.order_id".to_string(),
+                    Transformation::Identity
+                ),
+            ],
+            ambiguous: false,
+            indirect: vec![],
+        }]
+    );
+    assert_eq!(
+        lineage[4].as_ref().expect("Failed to get lineage").columns,
+        vec![ColumnLineage {
+            name: "item_name".to_string(),
+            upstream: vec![(
+                "prod.platform.order_items.item_name".to_string(),
+                Transformation::Identity
+            )],
+            ambiguous: false,
+            indirect: vec![],
+        }]
+    );
+}
+#[test]
+fn indirect_lineage() {
+    let sql = r#"
+select orders.id, COUNT(order_items.id) as item_count
+from orders, integrations.order_items
+where order_items.order_id = orders.id
+group by order_items.order_id;
+select id, rank() over (partition by customer_name order by price) as position
+from orders;
+select sum(price) over w as running_total from orders window w as (order by id);
+with expensive as (select id from orders where price > 100)
+select id from expensive;"#;
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+    let mut analytics = QueryAnalytics::from(columns);
+    let lineage = analytics
+        .get_query_lineage(sql.to_string())
+        .expect("Failed to get lineage");
+    let filters = vec![
+        "prod.integrations.order_items.order_id".to_string(),
+        "prod.integrations.orders.id".to_string(),
+    ];
+    assert_eq!(
+        lineage[0].columns,
+        vec![
+            ColumnLineage {
+                name: "id".to_string(),
+                upstream: vec![(
+                    "prod.integrations.orders.id".to_string(),
+                    Transformation::Identity
+                )],
+                ambiguous: false,
+                indirect: filters.clone(),
+            },
+            ColumnLineage {
+                name: "item_count".to_string(),
+                upstream: vec![(
+                    "prod.integrations.order_items.id".to_string(),
+                    Transformation::Aggregation
+                )],
+                ambiguous: false,
+                indirect: filters,
+            },
+        ]
+    );
+    assert_eq!(
+        lineage[1].columns,
+        vec![
+            ColumnLineage {
+                name: "id".to_string(),
+                upstream: vec![(
+                    "prod.integrations.orders.id".to_string(),
+                    Transformation::Identity
+                )],
+                ambiguous: false,
+                indirect: vec![],
+            },
+            ColumnLineage {
+                name: "position".to_string(),
+                upstream: vec![],
+                ambiguous: false,
+                indirect: vec![
+                    "prod.integrations.orders.customer_name".to_string(),
+                    "prod.integrations.orders.price".to_string(),
+                ],
+            },
+        ]
+    );
+    assert_eq!(
+        lineage[2].columns,
+        vec![ColumnLineage {
+            name: "running_total".to_string(),
+            upstream: vec![(
+                "prod.integrations.orders.price".to_string(),
+                Transformation::Window
+            )],
+            ambiguous: false,
+            indirect: vec!["prod.integrations.orders.id".to_string()],
+        }]
+    );
+    // Indirect lineage carries over through a common table expression.
+    assert_eq!(
+        lineage[3].columns,
+        vec![ColumnLineage {
+            name: "id".to_string(),
+            upstream: vec![(
+                "prod.integrations.orders.id".to_string(),
+                Transformation::Identity
+            )],
+            ambiguous: false,
+            indirect: vec!["prod.integrations.orders.price".to_string()],
+        }]
+    );
+}
+#[test]
+fn transformations() {
+    let sql = r#"
+with renamed as (select id as order_id, price from orders)
+select
+  order_id,
+  order_id as renamed_again,
+  cast(price as int) as int_price,
+  price * 2 as double_price,
+  coalesce(price, 0) as known_price,
+  sha256(order_id) as hashed_id,
+  sum(price) as total,
+  sum(price) over (partition by order_id) as running_total
+from renamed
+group by order_id, price;"#;
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+    let mut analytics = QueryAnalytics::from(columns);
+    let lineage = analytics
+        .get_query_lineage(sql.to_string())
+        .expect("Failed to get lineage");
+    let transformations = lineage[0]
+        .columns
+        .iter()
+        .map(|column| (column.name.as_str(), column.upstream[0].1))
+        .collect::<Vec<(&str, Transformation)>>();
+    assert_eq!(
+        transformations,
+        vec![
+            ("order_id", Transformation::Rename),
+            ("renamed_again", Transformation::Rename),
+            ("int_price", Transformation::Cast),
+            ("double_price", Transformation::Arithmetic),
+            ("known_price", Transformation::Conditional),
+            ("hashed_id", Transformation::Masking),
+            ("total", Transformation::Aggregation),
+            ("running_total", Transformation::Window),
+        ]
+    );
+}
+#[test]
+fn function_catalog() {
+    let sql = r#"
+select
+  dateadd(day, price, id) as shifted,
+  lag(price, 1) over (order by id) as previous_price,
+  mask(customer_name, item_name) as masked_name,
+  upper(item_name) as item
+from orders;"#;
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+    let mut analytics = QueryAnalytics::from(columns).with_functions(vec![FunctionDefinition {
+        function_name: "MASK".to_string(),
+        transformation: Transformation::Masking,
+        arguments: vec![1],
+    }]);
+    let lineage = analytics
+        .get_query_lineage(sql.to_string())
+        .expect("Failed to get lineage");
+    let upstream = lineage[0]
+        .columns
+        .iter()
+        .map(|column| (column.name.as_str(), column.upstream.clone()))
+        .collect::<Vec<(&str, Vec<(String, Transformation)>)>>();
+    assert_eq!(
+        upstream,
+        vec![
+            (
+                "shifted",
+                vec![
+                    (
+                        "prod.integrations.orders.price".to_string(),
+                        Transformation::Arithmetic
+                    ),
+                    (
+                        "prod.integrations.orders.id".to_string(),
+                        Transformation::Arithmetic
+                    ),
+                ]
+            ),
+            (
+                "previous_price",
+                vec![(
+                    "prod.integrations.orders.price".to_string(),
+                    Transformation::Window
+                )]
+            ),
+            (
+                "masked_name",
+                vec![(
+                    "prod.integrations.orders.customer_name".to_string(),
+                    Transformation::Masking
+                )]
+            ),
+            (
+                "item",
+                vec![(
+                    "prod.integrations.orders.item_name".to_string(),
+                    Transformation::Arithmetic
+                )]
+            ),
+        ]
+    );
+}
+#[test]
+fn dialects() {
+    let snowflake_columns = vec![
+        FullColumn {
+            database_name: "PROD".to_string(),
+            schema_name: "RAW".to_string(),
+            table_name: "EVENTS".to_string(),
+            column_name: "ID".to_string(),
+        },
+        FullColumn {
+            database_name: "PROD".to_string(),
+            schema_name: "RAW".to_string(),
+            table_name: "EVENTS".to_string(),
+            column_name: "PAYLOAD".to_string(),
+        },
+    ];
+    let mut analytics = QueryAnalytics::from(snowflake_columns).with_dialect(Dialect::Snowflake);
+    let lineage = analytics
+        .get_query_lineage(
+            r#"
+select payload:customer::string as customer
+from raw.events
+qualify row_number() over (partition by id order by payload:ts desc) = 1;"#
+                .to_string(),
+        )
+        .expect("Failed to get lineage");
+    assert_eq!(
+        lineage[0].columns,
+        vec![ColumnLineage {
+            name: "CUSTOMER".to_string(),
+            upstream: vec![(
+                "PROD.RAW.EVENTS.PAYLOAD".to_string(),
+                Transformation::Arithmetic
+            )],
+            ambiguous: false,
+            indirect: vec![
+                "PROD.RAW.EVENTS.ID".to_string(),
+                "PROD.RAW.EVENTS.PAYLOAD".to_string(),
+            ],
+        }]
+    );
+
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+    let mut analytics = QueryAnalytics::from(columns.clone()).with_dialect(Dialect::BigQuery);
+    let lineage = analytics
+        .get_query_lineage("select `id` from `integrations`.`orders`;".to_string())
+        .expect("Failed to get lineage");
+    assert_eq!(
+        lineage[0].columns[0].upstream,
+        vec![(
+            "prod.integrations.orders.id".to_string(),
+            Transformation::Identity
+        )]
+    );
+
+    // Postgres folds unquoted names to lower case, but not quoted ones.
+    let mut analytics = QueryAnalytics::from(columns).with_dialect(Dialect::Postgres);
+    let lineage = analytics.collect_query_lineage(
+        r#"
+select ID, Price from Orders;
+select "ID" from orders;"#,
+    );
+    let names = lineage[0]
+        .as_ref()
+        .expect("Failed to get lineage")
+        .columns
+        .iter()
+        .map(|column| column.name.as_str())
+        .collect::<Vec<&str>>();
+    assert_eq!(names, vec!["id", "price"]);
+    assert_eq!(
+        lineage[1].as_ref().expect("Failed to get lineage").columns[0].upstream,
+        vec![]
+    );
+}
+
+#[test]
+fn identifier_case() {
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+    let id = vec![(
+        "prod.integrations.orders.id".to_string(),
+        Transformation::Identity,
+    )];
+
+    // Unquoted names match in any case, quoted ones only in the same case.
+    let mut analytics = QueryAnalytics::from(columns.clone());
+    let lineage = analytics.collect_query_lineage(
+        r#"
+select ID from Integrations.Orders;
+select O.Id from orders as o;
+select "ID" from orders;"#,
+    );
+    let upstream = lineage
+        .into_iter()
+        .map(|lineage| {
+            lineage.expect("Failed to get lineage").columns[0]
+                .upstream
+                .clone()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(upstream, vec![id.clone(), id.clone(), vec![]]);
+
+    // MySQL matches names case-insensitively, quoted or not.
+    let mut analytics = QueryAnalytics::from(columns.clone()).with_dialect(Dialect::MySql);
+    let lineage = analytics
+        .get_query_lineage("select `ID` from `ORDERS`;".to_string())
+        .expect("Failed to get lineage");
+    assert_eq!(lineage[0].columns[0].upstream, id);
+
+    // Snowflake folds unquoted names to upper case, which a lower case
+    // catalog only matches when quoted.
+    let mut analytics = QueryAnalytics::from(columns).with_dialect(Dialect::Snowflake);
+    let lineage = analytics
+        .get_query_lineage(r#"select "id" from "integrations"."orders";"#.to_string())
+        .expect("Failed to get lineage");
+    assert_eq!(lineage[0].columns[0].upstream, id);
+    assert!(analytics
+        .get_query_lineage("select id from integrations.orders;".to_string())
+        .is_err());
+}
+
+#[test]
+fn qualified_table_names() {
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+    let id = vec![(
+        "prod.integrations.orders.id".to_string(),
+        Transformation::Identity,
+    )];
+
+    let mut analytics = QueryAnalytics::from(columns.clone());
+    let lineage = analytics.collect_query_lineage(
+        r#"
+select id from orders;
+select id from integrations.orders;
+select id from prod.integrations.orders;
+select orders.id from prod.integrations.orders;
+select integrations.orders.id from prod.integrations.orders;"#,
+    );
+    for lineage in lineage {
+        assert_eq!(
+            lineage.expect("Failed to get lineage").columns[0].upstream,
+            id
+        );
+    }
+    assert!(analytics
+        .get_query_lineage("select id from dev.integrations.orders;".to_string())
+        .is_err());
+    assert!(analytics
+        .get_query_lineage("select id from prod.orders;".to_string())
+        .is_err());
+
+    // BigQuery names a table `project.dataset.table`, quoted part by part
+    // or as a whole.
+    let mut analytics = QueryAnalytics::from(columns).with_dialect(Dialect::BigQuery);
+    let lineage = analytics.collect_query_lineage(
+        r#"
+select id from `prod`.`integrations`.`orders`;
+select o.id from `prod.integrations.orders` as o;
+select orders.id from `prod.integrations.orders`;"#,
+    );
+    for lineage in lineage {
+        assert_eq!(
+            lineage.expect("Failed to get lineage").columns[0].upstream,
+            id
+        );
+    }
+}
+
+#[test]
+fn search_path() {
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+    let upstream = |analytics: &mut QueryAnalytics, query: &str| {
+        analytics
+            .get_query_lineage(query.to_string())
+            .map(|lineage| {
+                lineage[0]
+                    .columns
+                    .iter()
+                    .flat_map(|column| column.upstream.iter().map(|(name, _)| name.clone()))
+                    .collect::<Vec<String>>()
+            })
+    };
+
+    // Without a search path the first table of that name in the catalog wins.
+    let mut analytics = QueryAnalytics::from(columns.clone());
+    assert_eq!(
+        upstream(&mut analytics, "select * from order_items;").expect("Failed to get lineage"),
+        vec![
+            "prod.integrations.order_items.id",
+            "prod.integrations.order_items.order_id",
+            "prod.integrations.order_items.date",
+            "prod.integrations.order_items.count",
+        ]
+    );
+
+    let mut analytics = QueryAnalytics::from(columns.clone())
+        .with_database(Some("prod".to_string()))
+        .with_search_path(vec!["staging".to_string(), "platform".to_string()]);
+    assert_eq!(
+        upstream(&mut analytics, "select item_name from order_items;")
+            .expect("Failed to get lineage"),
+        vec!["prod.platform.order_items.item_name"]
+    );
+    // Names with a schema don't use the search path.
+    assert_eq!(
+        upstream(&mut analytics, "select id from integrations.order_items;")
+            .expect("Failed to get lineage"),
+        vec!["prod.integrations.order_items.id"]
+    );
+    // Nor can the search path find tables outside of it.
+    assert!(upstream(&mut analytics, "select id from orders;").is_err());
+
+    let mut analytics = QueryAnalytics::from(columns).with_database(Some("dev".to_string()));
+    assert!(upstream(&mut analytics, "select id from integrations.orders;").is_err());
+    assert_eq!(
+        upstream(&mut analytics, "select id from prod.integrations.orders;")
+            .expect("Failed to get lineage"),
+        vec!["prod.integrations.orders.id"]
+    );
+}
+
+#[test]
+fn session_statements() {
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+
+    let mut analytics = QueryAnalytics::from(columns.clone());
+    let lineage = analytics.collect_query_lineage(
+        r#"
+use prod;
+set search_path to staging, platform;
+select item_name from order_items;
+set search_path = 'integrations';
+select id from order_items;
+use dev;
+select id from order_items;"#,
+    );
+    assert!(lineage[0]
+        .as_ref()
+        .expect("Failed to get lineage")
+        .columns
+        .is_empty());
+    assert_eq!(
+        lineage[2].as_ref().expect("Failed to get lineage").columns[0].upstream,
+        vec![(
+            "prod.platform.order_items.item_name".to_string(),
+            Transformation::Identity
+        )]
+    );
+    assert_eq!(
+        lineage[4].as_ref().expect("Failed to get lineage").columns[0].upstream,
+        vec![(
+            "prod.integrations.order_items.id".to_string(),
+            Transformation::Identity
+        )]
+    );
+    assert!(matches!(
+        lineage[6],
+        Err(LineageError::UnknownTable { statement: 6, .. })
+    ));
+
+    // They only last for the rest of the run, so the same input is resolved
+    // the same way every time, starting from the configured search path.
+    let mut analytics =
+        QueryAnalytics::from(columns.clone()).with_search_path(vec!["integrations".to_string()]);
+    let sql = "select count from order_items; set search_path to platform;";
+    for _ in 0..2 {
+        let lineage = analytics
+            .get_query_lineage(sql.to_string())
+            .expect("Failed to get lineage");
+        assert_eq!(
+            lineage[0].columns[0].upstream,
+            vec![(
+                "prod.integrations.order_items.count".to_string(),
+                Transformation::Identity
+            )]
+        );
+    }
+
+    // MySQL databases are schemas.
+    let mut analytics = QueryAnalytics::from(columns).with_dialect(Dialect::MySql);
+    let lineage = analytics
+        .get_query_lineage("use platform; select item_name from order_items;".to_string())
+        .expect("Failed to get lineage");
+    assert_eq!(
+        lineage[1].columns[0].upstream,
+        vec![(
+            "prod.platform.order_items.item_name".to_string(),
+            Transformation::Identity
+        )]
+    );
+}
+
+#[test]
+fn insert_select() {
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+    let mut analytics = QueryAnalytics::from(columns);
+    let lineage = analytics
+        .get_query_lineage(
+            r#"
+insert into integrations.order_items (order_id, count)
+select id, price from integrations.orders;
+insert into platform.order_items
+select o.id, i.date, o.item_name, i.count
+from integrations.orders o join integrations.order_items i on i.order_id = o.id;
+insert into reports.daily select sum(price) as revenue from integrations.orders;"#
+                .to_string(),
+        )
+        .expect("Failed to get lineage");
+    let targets = lineage
+        .iter()
+        .map(|lineage| lineage.target.as_deref())
+        .collect::<Vec<Option<&str>>>();
+    assert_eq!(
+        targets,
+        vec![
+            Some("prod.integrations.order_items"),
+            Some("prod.platform.order_items"),
+            Some("reports.daily"),
+        ]
+    );
+    assert_eq!(
+        lineage[0].columns,
+        vec![
+            ColumnLineage {
+                name: "order_id".to_string(),
+                upstream: vec![(
+                    "prod.integrations.orders.id".to_string(),
+                    Transformation::Rename
+                )],
+                ambiguous: false,
+                indirect: vec![],
+            },
+            ColumnLineage {
+                name: "count".to_string(),
+                upstream: vec![(
+                    "prod.integrations.orders.price".to_string(),
+                    Transformation::Rename
+                )],
+                ambiguous: false,
+                indirect: vec![],
+            },
+        ]
+    );
+    let names = lineage[1]
+        .columns
+        .iter()
+        .map(|column| column.name.as_str())
+        .collect::<Vec<&str>>();
+    assert_eq!(names, vec!["order_id", "date", "item_name", "count"]);
+    assert_eq!(
+        lineage[1].columns[0].indirect,
+        vec![
+            "prod.integrations.order_items.order_id".to_string(),
+            "prod.integrations.orders.id".to_string(),
+        ]
+    );
+    assert_eq!(lineage[2].columns[0].name, "revenue");
+    assert_eq!(
+        analytics.dependency_map["prod.platform.order_items.item_name"],
+        vec![(
+            "prod.integrations.orders.item_name".to_string(),
+            Transformation::Identity
+        )]
+    );
+    assert_eq!(
+        analytics.dependency_map["reports.daily.revenue"],
+        vec![(
+            "prod.integrations.orders.price".to_string(),
+            Transformation::Aggregation
+        )]
+    );
+
+    // Columns of the source go into the table by position, whatever they
+    // are named.
+    let lineage = analytics.collect_query_lineage(
+        r#"
+insert into integrations.orders (id, price)
+select o.id, i.id from integrations.orders o, integrations.order_items i;
+insert into integrations.orders (id, price, item_name)
+select id, price from integrations.orders;
+insert into integrations.order_items select *, 1 from integrations.orders;"#,
+    );
+    assert_eq!(
+        lineage[0].as_ref().expect("Failed to get lineage").columns,
+        vec![
+            ColumnLineage {
+                name: "id".to_string(),
+                upstream: vec![(
+                    "prod.integrations.orders.id".to_string(),
+                    Transformation::Identity
+                )],
+                ambiguous: false,
+                indirect: vec![],
+            },
+            ColumnLineage {
+                name: "price".to_string(),
+                upstream: vec![(
+                    "prod.integrations.order_items.id".to_string(),
+                    Transformation::Rename
+                )],
+                ambiguous: false,
+                indirect: vec![],
+            },
+        ]
+    );
+    assert_eq!(
+        lineage[1],
+        Err(LineageError::ColumnCountMismatch {
+            statement: 1,
+            location: sqlparser::tokenizer::Location { line: 4, column: 1 },
+            target: "prod.integrations.orders".to_string(),
+            expected: 3,
+            found: 2,
+        })
+    );
+    // The table has fewer columns than the source returns.
+    assert!(matches!(
+        lineage[2],
+        Err(LineageError::ColumnCountMismatch {
+            expected: 4,
+            found: 5,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn duplicate_output_names() {
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+    let mut analytics = QueryAnalytics::from(columns);
+    let lineage = analytics
+        .get_query_lineage(
+            r#"
+select id, id from integrations.orders
+union all
+select price, item_name from integrations.orders;
+select a, b from (select id, price as id from integrations.orders) as t (a, b);
+with pairs (a, b) as (select id, price as id from integrations.orders)
+select b from pairs;"#
+                .to_string(),
+        )
+        .expect("Failed to get lineage");
+    // Every item of a SELECT is a column of its own, even when it has the
+    // name of another one.
+    assert_eq!(
+        lineage[0].columns,
+        vec![
+            ColumnLineage {
+                name: "id".to_string(),
+                upstream: vec![
+                    (
+                        "prod.integrations.orders.id".to_string(),
+                        Transformation::Identity
+                    ),
+                    (
+                        "prod.integrations.orders.price".to_string(),
+                        Transformation::Rename
+                    ),
+                ],
+                ambiguous: false,
+                indirect: vec![],
+            },
+            ColumnLineage {
+                name: "id".to_string(),
+                upstream: vec![
+                    (
+                        "prod.integrations.orders.id".to_string(),
+                        Transformation::Identity
+                    ),
+                    (
+                        "prod.integrations.orders.item_name".to_string(),
+                        Transformation::Rename
+                    ),
+                ],
+                ambiguous: false,
+                indirect: vec![],
+            },
+        ]
+    );
+    for lineage in &lineage[1..] {
+        assert_eq!(
+            lineage.columns.last().map(|column| &column.upstream),
+            Some(&vec![(
+                "prod.integrations.orders.price".to_string(),
+                Transformation::Rename
+            )])
+        );
+    }
+}
+
+#[test]
+fn create_table_as() {
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+    let mut analytics = QueryAnalytics::from(columns);
+    let lineage = analytics
+        .get_query_lineage(
+            r#"
+create table analytics.revenue as
+select item_name, sum(price) as revenue from integrations.orders group by item_name;
+create view analytics.top_items (item, total) as
+select item_name, revenue from analytics.revenue where revenue > 100;
+select total from top_items;
+set search_path to analytics;
+create table summary (item text, total int);
+insert into summary select * from top_items;"#
+                .to_string(),
+        )
+        .expect("Failed to get lineage");
+    let targets = lineage
+        .iter()
+        .map(|lineage| lineage.target.as_deref())
+        .collect::<Vec<Option<&str>>>();
+    assert_eq!(
+        targets,
+        vec![
+            Some("analytics.revenue"),
+            Some("analytics.top_items"),
+            None,
+            None,
+            Some("analytics.summary"),
+            Some("analytics.summary"),
+        ]
+    );
+    assert_eq!(
+        lineage[1].columns[1],
+        ColumnLineage {
+            name: "total".to_string(),
+            upstream: vec![(
+                "analytics.revenue.revenue".to_string(),
+                Transformation::Rename
+            )],
+            ambiguous: false,
+            indirect: vec!["analytics.revenue.revenue".to_string()],
+        }
+    );
+    assert_eq!(
+        lineage[2].columns[0].upstream,
+        vec![(
+            "analytics.top_items.total".to_string(),
+            Transformation::Identity
+        )]
+    );
+    assert!(lineage[4].columns.is_empty());
+    let names = lineage[5]
+        .columns
+        .iter()
+        .map(|column| column.name.as_str())
+        .collect::<Vec<&str>>();
+    assert_eq!(names, vec!["item", "total"]);
+    assert_eq!(
+        analytics.dependency_map["analytics.revenue.revenue"],
+        vec![(
+            "prod.integrations.orders.price".to_string(),
+            Transformation::Aggregation
+        )]
+    );
+    assert_eq!(
+        analytics.dependency_map["analytics.summary.total"],
+        vec![(
+            "analytics.top_items.total".to_string(),
+            Transformation::Identity
+        )]
+    );
+}
+
+#[test]
+fn merge() {
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+    let mut analytics = QueryAnalytics::from(columns);
+    let lineage = analytics
+        .get_query_lineage(
+            r#"
+merge into integrations.orders as t
+using platform.order_items as s on t.id = s.order_id
+when matched and s.count > 0 then update set item_name = s.item_name, price = t.price + s.count
+when matched then delete
+when not matched then insert (id, item_name) values (s.order_id, s.item_name);"#
+                .to_string(),
+        )
+        .expect("Failed to get lineage");
+    let on = vec![
+        "prod.integrations.orders.id".to_string(),
+        "prod.platform.order_items.order_id".to_string(),
+    ];
+    let mut matched = on.clone();
+    matched.push("prod.platform.order_items.count".to_string());
+    assert_eq!(
+        lineage[0].target.as_deref(),
+        Some("prod.integrations.orders")
+    );
+    assert_eq!(
+        lineage[0].clauses,
+        vec![
+            ClauseLineage {
+                kind: ClauseKind::MatchedUpdate,
+                predicate: Some("s.count > 0".to_string()),
+                columns: vec![
+                    ColumnLineage {
+                        name: "item_name".to_string(),
+                        upstream: vec![(
+                            "prod.platform.order_items.item_name".to_string(),
+                            Transformation::Identity
+                        )],
+                        ambiguous: false,
+                        indirect: matched.clone(),
+                    },
+                    ColumnLineage {
+                        name: "price".to_string(),
+                        upstream: vec![
+                            (
+                                "prod.integrations.orders.price".to_string(),
+                                Transformation::Arithmetic
+                            ),
+                            (
+                                "prod.platform.order_items.count".to_string(),
+                                Transformation::Arithmetic
+                            ),
+                        ],
+                        ambiguous: false,
+                        indirect: matched.clone(),
+                    },
+                ],
+            },
+            ClauseLineage {
+                kind: ClauseKind::NotMatchedInsert,
+                predicate: None,
+                columns: vec![
+                    ColumnLineage {
+                        name: "id".to_string(),
+                        upstream: vec![(
+                            "prod.platform.order_items.order_id".to_string(),
+                            Transformation::Rename
+                        )],
+                        ambiguous: false,
+                        indirect: on.clone(),
+                    },
+                    ColumnLineage {
+                        name: "item_name".to_string(),
+                        upstream: vec![(
+                            "prod.platform.order_items.item_name".to_string(),
+                            Transformation::Identity
+                        )],
+                        ambiguous: false,
+                        indirect: on,
+                    },
+                ],
+            },
+        ]
+    );
+    assert_eq!(
+        lineage[0].clauses[0].to_string(),
+        "WHEN MATCHED AND s.count > 0 THEN UPDATE"
+    );
+    assert_eq!(
+        lineage[0].clauses[1].to_string(),
+        "WHEN NOT MATCHED THEN INSERT"
+    );
+    let names = lineage[0]
+        .columns
+        .iter()
+        .map(|column| column.name.as_str())
+        .collect::<Vec<&str>>();
+    assert_eq!(names, vec!["item_name", "price", "id"]);
+    assert_eq!(
+        analytics.dependency_map["prod.integrations.orders.id"],
+        vec![(
+            "prod.platform.order_items.order_id".to_string(),
+            Transformation::Rename
+        )]
+    );
+
+    // There is no target row to read from when there is no match, so
+    // unqualified names in the clause only refer to the source.
+    let lineage = analytics
+        .get_query_lineage(
+            r#"
+merge into integrations.order_items t
+using platform.order_items s on t.order_id = s.order_id
+when not matched and count > 0 then insert (order_id, count) values (order_id, count);"#
+                .to_string(),
+        )
+        .expect("Failed to get lineage");
+    let indirect = vec![
+        "prod.integrations.order_items.order_id".to_string(),
+        "prod.platform.order_items.order_id".to_string(),
+        "prod.platform.order_items.count".to_string(),
+    ];
+    assert_eq!(
+        lineage[0].columns,
+        vec![
+            ColumnLineage {
+                name: "order_id".to_string(),
+                upstream: vec![(
+                    "prod.platform.order_items.order_id".to_string(),
+                    Transformation::Identity
+                )],
+                ambiguous: false,
+                indirect: indirect.clone(),
+            },
+            ColumnLineage {
+                name: "count".to_string(),
+                upstream: vec![(
+                    "prod.platform.order_items.count".to_string(),
+                    Transformation::Identity
+                )],
+                ambiguous: false,
+                indirect,
+            },
+        ]
+    );
+
+    assert_eq!(
+        analytics.get_query_lineage(
+            r#"
+merge into integrations.order_items t
+using platform.order_items s on t.order_id = s.order_id
+when not matched then insert (order_id, count) values (s.order_id);"#
+                .to_string(),
+        ),
+        Err(LineageError::ColumnCountMismatch {
+            statement: 0,
+            location: sqlparser::tokenizer::Location { line: 2, column: 1 },
+            target: "prod.integrations.order_items".to_string(),
+            expected: 2,
+            found: 1,
+        })
+    );
+}
+
+#[test]
+fn update_from() {
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+    let mut analytics = QueryAnalytics::from(columns);
+    let lineage = analytics
+        .get_query_lineage(
+            r#"
+update orders set price = oi.count * 2 from order_items oi where oi.order_id = orders.id;
+update integrations.orders o join platform.order_items i on i.order_id = o.id
+set o.item_name = i.item_name;"#
+                .to_string(),
+        )
+        .expect("Failed to get lineage");
+    assert_eq!(
+        lineage[0].target.as_deref(),
+        Some("prod.integrations.orders")
+    );
+    assert_eq!(
+        lineage[0].columns,
+        vec![ColumnLineage {
+            name: "price".to_string(),
+            upstream: vec![(
+                "prod.integrations.order_items.count".to_string(),
+                Transformation::Arithmetic
+            )],
+            ambiguous: false,
+            indirect: vec![
+                "prod.integrations.order_items.order_id".to_string(),
+                "prod.integrations.orders.id".to_string(),
+            ],
+        }]
+    );
+    assert_eq!(
+        lineage[1].columns,
+        vec![ColumnLineage {
+            name: "item_name".to_string(),
+            upstream: vec![(
+                "prod.platform.order_items.item_name".to_string(),
+                Transformation::Identity
+            )],
+            ambiguous: false,
+            indirect: vec![
+                "prod.platform.order_items.order_id".to_string(),
+                "prod.integrations.orders.id".to_string(),
+            ],
+        }]
+    );
+    assert_eq!(
+        analytics.dependency_map["prod.integrations.orders.price"],
+        vec![(
+            "prod.integrations.order_items.count".to_string(),
+            Transformation::Arithmetic
+        )]
+    );
+}
+
+#[test]
+fn subquery_expressions() {
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+    let mut analytics = QueryAnalytics::from(columns);
+    let lineage = analytics
+        .get_query_lineage(
+            r#"
+select
+  id,
+  (select max(date) from platform.order_items i where i.order_id = o.id) as last_ordered,
+  coalesce((select sum(count) from integrations.order_items where order_id = o.id), 0) as item_count
+from integrations.orders o
+where exists (select 1 from platform.order_items p where p.order_id = o.id)
+  and id in (select order_id from integrations.order_items)
+group by id
+having count(*) > (select count(*) from platform.order_items);"#
+                .to_string(),
+        )
+        .expect("Failed to get lineage");
+    assert_eq!(
+        lineage[0].columns,
+        vec![
+            ColumnLineage {
+                name: "id".to_string(),
+                upstream: vec![(
+                    "prod.integrations.orders.id".to_string(),
+                    Transformation::Identity
+                )],
+                ambiguous: false,
+                indirect: vec![
+                    "prod.integrations.orders.id".to_string(),
+                    "prod.platform.order_items.order_id".to_string(),
+                    "prod.integrations.order_items.order_id".to_string(),
+                ],
+            },
+            ColumnLineage {
+                name: "last_ordered".to_string(),
+                upstream: vec![(
+                    "prod.platform.order_items.date".to_string(),
+                    Transformation::Aggregation
+                )],
+                ambiguous: false,
+                indirect: vec![
+                    "prod.platform.order_items.order_id".to_string(),
+                    "prod.integrations.orders.id".to_string(),
+                    "prod.integrations.order_items.order_id".to_string(),
+                ],
+            },
+            ColumnLineage {
+                name: "item_count".to_string(),
+                upstream: vec![(
+                    "prod.integrations.order_items.count".to_string(),
+                    Transformation::Aggregation
+                )],
+                ambiguous: false,
+                indirect: vec![
+                    "prod.integrations.order_items.order_id".to_string(),
+                    "prod.integrations.orders.id".to_string(),
+                    "prod.platform.order_items.order_id".to_string(),
+                ],
+            },
+        ]
+    );
+
+    // Only correlated references reach the tables of the outer query.
+    assert!(matches!(
+        analytics.get_query_lineage(
+            "select (select x.id from platform.order_items) as id from integrations.orders;"
+                .to_string()
+        ),
+        Err(LineageError::UnknownTable { .. })
+    ));
+
+    // A relation of the subquery hides the relation of the outer query with
+    // the same name, even when only the outer one has the column.
+    let lineage = analytics
+        .get_query_lineage(
+            r#"
+select (select o.customer_name from integrations.order_items o) as x from integrations.orders o;
+select id from integrations.orders o
+where exists (select 1 from integrations.order_items o where o.price > 0);
+select (select i.nosuch from integrations.order_items i) as x from integrations.orders;"#
+                .to_string(),
+        )
+        .expect("Failed to get lineage");
+    assert_eq!(lineage[0].columns[0].upstream, vec![]);
+    assert_eq!(lineage[1].columns[0].indirect, Vec::<String>::new());
+    assert_eq!(lineage[2].columns[0].upstream, vec![]);
+}
+
+#[test]
+fn lateral_scopes() {
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+    let correlated = vec![
+        "prod.platform.order_items.order_id".to_string(),
+        "prod.integrations.orders.id".to_string(),
+    ];
+
+    let mut analytics = QueryAnalytics::from(columns.clone());
+    let lineage = analytics
+        .get_query_lineage(
+            r#"
+select o.id, l.*
+from integrations.orders o
+cross join lateral (
+  select sum(count) as total from platform.order_items i where i.order_id = o.id
+) l;"#
+                .to_string(),
+        )
+        .expect("Failed to get lineage");
+    assert_eq!(
+        lineage[0].columns[1],
+        ColumnLineage {
+            name: "total".to_string(),
+            upstream: vec![(
+                "prod.platform.order_items.count".to_string(),
+                Transformation::Aggregation
+            )],
+            ambiguous: false,
+            indirect: correlated.clone(),
+        }
+    );
+
+    // Without LATERAL a derived table can't see the relations next to it.
+    assert!(matches!(
+        analytics.get_query_lineage(
+            "select d.* from integrations.orders o, (select o.id from platform.order_items) d;"
+                .to_string()
+        ),
+        Err(LineageError::UnknownTable { .. })
+    ));
+
+    // The relations of a subquery shadow the ones of the outer query with
+    // the same name.
+    let lineage = analytics
+        .get_query_lineage(
+            r#"
+select id from integrations.orders o
+where exists (select 1 from platform.order_items o where o.item_name = 'x');"#
+                .to_string(),
+        )
+        .expect("Failed to get lineage");
+    assert_eq!(
+        lineage[0].columns[0].indirect,
+        vec!["prod.platform.order_items.item_name".to_string()]
+    );
+    // So do the relations of a LATERAL derived table, even when only the
+    // relation before it has the column.
+    let lineage = analytics
+        .get_query_lineage(
+            r#"
+select t.a
+from integrations.orders o
+cross join lateral (select o.price as a from integrations.order_items o) t;"#
+                .to_string(),
+        )
+        .expect("Failed to get lineage");
+    assert_eq!(lineage[0].columns[0].upstream, vec![]);
+
+    let mut analytics = QueryAnalytics::from(columns).with_dialect(Dialect::MsSql);
+    let lineage = analytics
+        .get_query_lineage(
+            r#"
+select a.*
+from integrations.orders o
+cross apply (
+  select top 1 date from platform.order_items p where p.order_id = o.id order by date desc
+) a;"#
+                .to_string(),
+        )
+        .expect("Failed to get lineage");
+    assert_eq!(
+        lineage[0].columns,
+        vec![ColumnLineage {
+            name: "date".to_string(),
+            upstream: vec![(
+                "prod.platform.order_items.date".to_string(),
+                Transformation::Identity
+            )],
+            ambiguous: false,
+            indirect: correlated,
+        }]
+    );
+}
+
+#[test]
+fn derived_table_columns() {
+    let mut rdr = csv::Reader::from_path("columns.csv").expect("Failed to load csv file");
+    let columns: Vec<FullColumn> = rdr
+        .deserialize::<FullColumn>()
+        .collect::<Result<Vec<FullColumn>, csv::Error>>()
+        .expect("Failed to parse csv");
+    let mut analytics = QueryAnalytics::from(columns);
+    let lineage = analytics
+        .get_query_lineage(
+            r#"
+select my_order_id from (select order_id as my_order_id from integrations.order_items) d;
+select t.oid, t.n
+from (select order_id, count(*) from platform.order_items group by order_id) as t (oid, n);
+select d.order_id from (select order_id as my_order_id from integrations.order_items) d;"#
+                .to_string(),
+        )
+        .expect("Failed to get lineage");
+    assert_eq!(
+        lineage[0].columns[0].upstream,
+        vec![(
+            "prod.integrations.order_items.order_id".to_string(),
+            Transformation::Rename
+        )]
+    );
+    let group_by = vec!["prod.platform.order_items.order_id".to_string()];
+    assert_eq!(
+        lineage[1].columns,
+        vec![
+            ColumnLineage {
+                name: "oid".to_string(),
+                upstream: vec![(
+                    "prod.platform.order_items.order_id".to_string(),
+                    Transformation::Rename
+                )],
+                ambiguous: false,
+                indirect: group_by.clone(),
+            },
+            ColumnLineage {
+                name: "n".to_string(),
+                upstream: vec![],
+                ambiguous: false,
+                indirect: group_by,
+            },
+        ]
+    );
+    // The derived table only exposes its own output columns.
+    assert_eq!(lineage[2].columns[0].upstream, vec![]);
+
+    // Each derived table is resolved once, however deep they nest.
+    let mut sql = "select id, price from integrations.orders".to_string();
+    for depth in 0..12 {
+        sql = format!("select id, price from ({sql}) d{depth} where price > 0");
+    }
+    let lineage = analytics
+        .get_query_lineage(sql)
+        .expect("Failed to get lineage");
+    assert_eq!(
+        lineage[0].columns[1].upstream,
+        vec![(
+            "prod.integrations.orders.price".to_string(),
+            Transformation::Identity
+        )]
+    );
+    assert_eq!(
+        lineage[0].columns[1].indirect,
+        vec!["prod.integrations.orders.price".to_string()]
+    );
+}